@@ -0,0 +1,366 @@
+//! Bencode: декодер, не копирующий строки (они - срезы исходного Bytes), и кодировщик.
+//! Порядок ключей словаря сохраняется как во входных данных, поэтому encode(decode(x)) == x,
+//! и info-hash можно считать по перекодированному словарю info.
+
+use bytes::Bytes;
+
+/// Глубже в нормальных .torrent, ответах трекеров и KRPC не бывает
+pub const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum BencodeError {
+    #[fail(display = "unexpected end of bencoded data")]
+    Eof,
+    #[fail(display = "invalid {} at byte {}", _0, _1)]
+    Invalid(&'static str, usize),
+    #[fail(display = "bencoded data nested deeper than {} levels", _0)]
+    TooDeep(usize),
+    #[fail(display = "trailing data at byte {}", _0)]
+    Trailing(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Bytes),
+    List(Vec<Value>),
+    Dict(Dict),
+}
+
+/// Словарь с ключами в исходном порядке
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Dict {
+    entries: Vec<(Bytes, Value)>,
+}
+
+impl Dict {
+    pub fn new() -> Self {
+        Dict::default()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.entries.iter().find(|(k, _)| k.as_ref() == key).map(|(_, v)| v)
+    }
+
+    /// Новый ключ добавляется в конец, существующий заменяется на месте
+    pub fn insert<K: Into<Bytes>, V: Into<Value>>(&mut self, key: K, value: V) {
+        let key = key.into();
+        let value = value.into();
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&Bytes, &Value)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get_int(&self, key: &[u8]) -> Option<i64> {
+        self.get(key).and_then(Value::as_int)
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> Option<&Bytes> {
+        self.get(key).and_then(Value::as_bytes)
+    }
+
+    pub fn get_str(&self, key: &[u8]) -> Option<&str> {
+        self.get(key).and_then(Value::as_str)
+    }
+
+    pub fn get_list(&self, key: &[u8]) -> Option<&[Value]> {
+        self.get(key).and_then(Value::as_list)
+    }
+
+    pub fn get_dict(&self, key: &[u8]) -> Option<&Dict> {
+        self.get(key).and_then(Value::as_dict)
+    }
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&Bytes> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+
+    pub fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(n) => out.extend_from_slice(format!("i{}e", n).as_bytes()),
+            Value::Bytes(bytes) => encode_bytes(bytes, out),
+            Value::List(list) => {
+                out.push(b'l');
+                for value in list {
+                    value.encode_to(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in &dict.entries {
+                    encode_bytes(key, out);
+                    value.encode_to(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<Bytes> for Value {
+    fn from(bytes: Bytes) -> Self {
+        Value::Bytes(bytes)
+    }
+}
+
+impl<'a> From<&'a [u8]> for Value {
+    fn from(bytes: &'a [u8]) -> Self {
+        Value::Bytes(Bytes::from(bytes))
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(text: &'a str) -> Self {
+        Value::Bytes(Bytes::from(text))
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(list: Vec<Value>) -> Self {
+        Value::List(list)
+    }
+}
+
+impl From<Dict> for Value {
+    fn from(dict: Dict) -> Self {
+        Value::Dict(dict)
+    }
+}
+
+struct Decoder<'a> {
+    input: &'a Bytes,
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn peek(&self) -> Result<u8, BencodeError> {
+        self.input.get(self.pos).cloned().ok_or(BencodeError::Eof)
+    }
+
+    /// Число до терминатора; ведущие нули и "-0" не допускаются, чтобы перекодирование давало те же байты
+    fn number(&mut self, terminator: u8, what: &'static str) -> Result<i64, BencodeError> {
+        let start = self.pos;
+        let end = self.input[start..].iter().position(|&b| b == terminator)
+            .map(|p| start + p)
+            .ok_or(BencodeError::Eof)?;
+        let text = std::str::from_utf8(&self.input[start..end])
+            .map_err(|_| BencodeError::Invalid(what, start))?;
+        let digits = if text.starts_with('-') { &text[1..] } else { text };
+        let canonical = !digits.is_empty()
+            && digits.bytes().all(|b| b.is_ascii_digit())
+            && (digits == "0" || !digits.starts_with('0'))
+            && text != "-0";
+        if !canonical {
+            return Err(BencodeError::Invalid(what, start));
+        }
+        let n = text.parse().map_err(|_| BencodeError::Invalid(what, start))?;
+        self.pos = end + 1;
+        Ok(n)
+    }
+
+    fn bytes(&mut self) -> Result<Bytes, BencodeError> {
+        let start = self.pos;
+        let length = self.number(b':', "string length")?;
+        if length < 0 {
+            return Err(BencodeError::Invalid("string length", start));
+        }
+        let length = length as usize;
+        if self.input.len() - self.pos < length {
+            return Err(BencodeError::Eof);
+        }
+        let bytes = self.input.slice(self.pos, self.pos + length);
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, BencodeError> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                Ok(Value::Int(self.number(b'e', "integer")?))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.bytes()?)),
+            b'l' | b'd' if depth >= MAX_DEPTH => Err(BencodeError::TooDeep(MAX_DEPTH)),
+            b'l' => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = Dict::new();
+                while self.peek()? != b'e' {
+                    let start = self.pos;
+                    if !self.peek()?.is_ascii_digit() {
+                        return Err(BencodeError::Invalid("dictionary key", start));
+                    }
+                    let key = self.bytes()?;
+                    if dict.get(&key).is_some() {
+                        return Err(BencodeError::Invalid("duplicate dictionary key", start));
+                    }
+                    let value = self.value(depth + 1)?;
+                    dict.entries.push((key, value));
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            _ => Err(BencodeError::Invalid("value", self.pos)),
+        }
+    }
+}
+
+/// Одно значение в начале input и сколько байт оно заняло; дальше могут идти другие данные
+/// (как у ut_metadata, где за словарем идет кусок метаданных)
+pub fn decode_prefix(input: &Bytes) -> Result<(Value, usize), BencodeError> {
+    let mut decoder = Decoder { input, pos: 0 };
+    let value = decoder.value(0)?;
+    Ok((value, decoder.pos))
+}
+
+/// Ровно одно значение на весь input
+pub fn decode(input: &Bytes) -> Result<Value, BencodeError> {
+    let (value, length) = decode_prefix(input)?;
+    if length != input.len() {
+        return Err(BencodeError::Trailing(length));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_str(input: &'static [u8]) -> Result<Value, BencodeError> {
+        decode(&Bytes::from_static(input))
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut dict = Dict::new();
+        dict.insert("zeta", 1);
+        dict.insert("alpha", vec![Value::from("x"), Value::from(-5)]);
+        assert_eq!(Ok(Value::Dict(dict)), decode_str(b"d4:zetai1e5:alphal1:xi-5eee"));
+        assert_eq!(Ok(Value::from("")), decode_str(b"0:"));
+        assert_eq!(Ok(Value::Int(0)), decode_str(b"i0e"));
+    }
+
+    #[test]
+    fn test_round_trip_preserves_order() {
+        let input = Bytes::from_static(b"d4:infod6:lengthi10e4:name3:abc12:piece lengthi16384ee1:ai1ee");
+        let value = decode(&input).unwrap();
+        assert_eq!(input.as_ref(), value.encode().as_slice());
+        let info = value.as_dict().unwrap().get_dict(b"info").unwrap();
+        assert_eq!(Some("abc"), info.get_str(b"name"));
+        assert_eq!(
+            b"d6:lengthi10e4:name3:abc12:piece lengthi16384ee".as_ref(),
+            Value::Dict(info.clone()).encode().as_slice()
+        );
+    }
+
+    #[test]
+    fn test_zero_copy() {
+        //короткие срезы Bytes хранит внутри себя, поэтому строка длинная
+        let input = Bytes::from(format!("l40:{}e", "x".repeat(40)));
+        let value = decode(&input).unwrap();
+        let string = value.as_list().unwrap()[0].as_bytes().unwrap();
+        assert_eq!(input[4..].as_ptr(), string.as_ptr());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Err(BencodeError::Eof), decode_str(b"d3:key"));
+        assert_eq!(Err(BencodeError::Eof), decode_str(b"5:abc"));
+        assert_eq!(Err(BencodeError::Invalid("integer", 1)), decode_str(b"i03e"));
+        assert_eq!(Err(BencodeError::Invalid("integer", 1)), decode_str(b"i-0e"));
+        assert_eq!(Err(BencodeError::Invalid("integer", 1)), decode_str(b"ie"));
+        assert_eq!(Err(BencodeError::Invalid("dictionary key", 1)), decode_str(b"di1ei2ee"));
+        assert_eq!(Err(BencodeError::Invalid("duplicate dictionary key", 7)), decode_str(b"d1:ai1e1:ai2ee"));
+        assert_eq!(Err(BencodeError::Invalid("value", 0)), decode_str(b"x"));
+        assert_eq!(Err(BencodeError::Trailing(3)), decode_str(b"i1eXYZ"));
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let nested = |depth: usize| {
+            let mut input = vec![b'l'; depth];
+            input.extend(vec![b'e'; depth]);
+            decode(&Bytes::from(input))
+        };
+        assert!(nested(MAX_DEPTH).is_ok());
+        assert_eq!(Err(BencodeError::TooDeep(MAX_DEPTH)), nested(MAX_DEPTH + 1));
+    }
+
+    #[test]
+    fn test_prefix() {
+        let input = Bytes::from_static(b"d8:msg_typei1e5:piecei0eeRAWDATA");
+        let (value, length) = decode_prefix(&input).unwrap();
+        assert_eq!(Some(1), value.as_dict().unwrap().get_int(b"msg_type"));
+        assert_eq!(b"RAWDATA".as_ref(), &input[length..]);
+    }
+}
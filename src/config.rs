@@ -0,0 +1,330 @@
+extern crate toml;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use torrent::Settings;
+
+const DEFAULT_FILE: &str = "media-service.toml";
+const ENV_PREFIX: &str = "MEDIA_SERVICE_";
+
+pub const USAGE: &str = "Usage: media-service [OPTIONS]
+
+Options (each can also be set in the config file or as MEDIA_SERVICE_<NAME> environment variable):
+    --config <FILE>                 config file, media-service.toml by default
+    --bind <ADDR>                   HTTP listen address
+    --metainfo-dir <DIR>            where .torrent files are kept
+    --max-upload-size <BYTES>       maximum size of an uploaded .torrent
+    --max-create-size <BYTES>       maximum size of content uploaded to create a torrent
+    --upload-field <NAME>           multipart field with the .torrent, \"file\" by default
+    --fetch-timeout <SECONDS>       time limit for downloading a .torrent by URL
+    --data-dir <DIR>                where downloaded content is kept
+    --peer-port <PORT>              port announced to trackers
+    --max-connections <N>           total peer connections
+    --max-peers-per-torrent <N>     peer connections per torrent
+    --connect-timeout <SECONDS>     time limit for a TCP connection to a peer
+    --handshake-timeout <SECONDS>   time limit for the peer handshake exchange
+    --download-rate <BYTES/S>       0 means unlimited
+    --upload-rate <BYTES/S>         0 means unlimited
+    --user-agent <STRING>           User-Agent for tracker requests
+";
+
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    #[fail(display = "{}", _0)]
+    Help(&'static str),
+    #[fail(display = "can't read config file {}: {}", _0, _1)]
+    Read(String, io::Error),
+    #[fail(display = "invalid config file {}: {}", _0, _1)]
+    Parse(String, toml::de::Error),
+    #[fail(display = "unknown option {}", _0)]
+    Unknown(String),
+    #[fail(display = "missing value for {}", _0)]
+    MissingValue(String),
+    #[fail(display = "invalid value {:?} for {}: {}", value, key, reason)]
+    Value { key: String, value: String, reason: String },
+    #[fail(display = "{}", _0)]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    pub metainfo_dir: PathBuf,
+    pub max_upload_size: usize,
+    pub max_create_size: usize,
+    pub upload_field: String,
+    pub fetch_timeout: u64,
+    pub torrent: Settings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "127.0.0.1:8088".to_string(),
+            metainfo_dir: PathBuf::from("torrents"),
+            max_upload_size: 10 * 1024 * 1024,
+            max_create_size: 1024 * 1024 * 1024,
+            upload_field: "file".to_string(),
+            fetch_timeout: 30,
+            torrent: Settings::default(),
+        }
+    }
+}
+
+const KEYS: &[&str] = &[
+    "bind",
+    "metainfo_dir",
+    "max_upload_size",
+    "max_create_size",
+    "upload_field",
+    "fetch_timeout",
+    "data_dir",
+    "peer_port",
+    "max_connections",
+    "max_peers_per_torrent",
+    "connect_timeout",
+    "handshake_timeout",
+    "download_rate",
+    "upload_rate",
+    "user_agent",
+];
+
+fn parse<T>(key: &str, value: &str) -> Result<T, ConfigError>
+    where T: std::str::FromStr, T::Err: ToString {
+    value.parse().map_err(|e: T::Err| ConfigError::Value {
+        key: key.to_string(),
+        value: value.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// --some-option value | --some-option=value -> ("some_option", "value")
+fn parse_args<A: IntoIterator<Item=String>>(args: A) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut ret = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Err(ConfigError::Help(USAGE));
+        }
+        if !arg.starts_with("--") {
+            return Err(ConfigError::Unknown(arg));
+        }
+        let (name, value) = match arg.find('=') {
+            Some(pos) => (arg[2..pos].to_string(), arg[pos + 1..].to_string()),
+            None => {
+                let value = args.next().ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                (arg[2..].to_string(), value)
+            }
+        };
+        ret.push((name.replace('-', "_"), value));
+    }
+    Ok(ret)
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let name = path.display().to_string();
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(name.clone(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(name, e))
+    }
+
+    /// Порядок: значения по умолчанию < файл < переменные окружения < командная строка
+    pub fn load<A, V>(args: A, vars: V) -> Result<Self, ConfigError>
+        where A: IntoIterator<Item=String>,
+              V: IntoIterator<Item=(String, String)> {
+        let args = parse_args(args)?;
+        let vars: HashMap<String, String> = vars.into_iter().collect();
+        let file = args.iter()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| vars.get(&format!("{}CONFIG", ENV_PREFIX)).cloned());
+        let mut config = match file {
+            Some(file) => Config::from_file(Path::new(&file))?,
+            None if Path::new(DEFAULT_FILE).exists() => Config::from_file(Path::new(DEFAULT_FILE))?,
+            None => Config::default(),
+        };
+        for key in KEYS {
+            if let Some(value) = vars.get(&format!("{}{}", ENV_PREFIX, key.to_uppercase())) {
+                config.set(key, value)?;
+            }
+        }
+        for (key, value) in args.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "bind" => self.bind = value.to_string(),
+            "metainfo_dir" => self.metainfo_dir = PathBuf::from(value),
+            "max_upload_size" => self.max_upload_size = parse(key, value)?,
+            "max_create_size" => self.max_create_size = parse(key, value)?,
+            "upload_field" => self.upload_field = value.to_string(),
+            "fetch_timeout" => self.fetch_timeout = parse(key, value)?,
+            "data_dir" => self.torrent.data_dir = PathBuf::from(value),
+            "peer_port" => self.torrent.peer_port = parse(key, value)?,
+            "max_connections" => self.torrent.max_connections = parse(key, value)?,
+            "max_peers_per_torrent" => self.torrent.max_peers_per_torrent = parse(key, value)?,
+            "connect_timeout" => self.torrent.connect_timeout = parse(key, value)?,
+            "handshake_timeout" => self.torrent.handshake_timeout = parse(key, value)?,
+            "download_rate" => self.torrent.download_rate = parse(key, value)?,
+            "upload_rate" => self.torrent.upload_rate = parse(key, value)?,
+            "user_agent" => self.torrent.user_agent = value.to_string(),
+            _ => return Err(ConfigError::Unknown(format!("--{}", key.replace('_', "-")))),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.bind.parse::<SocketAddr>().map_err(|e| ConfigError::Value {
+            key: "bind".to_string(),
+            value: self.bind.clone(),
+            reason: e.to_string(),
+        })?;
+        if self.torrent.peer_port == 0 {
+            return Err(ConfigError::Invalid("peer_port must not be 0".to_string()));
+        }
+        if self.max_upload_size == 0 {
+            return Err(ConfigError::Invalid("max_upload_size must be greater than 0".to_string()));
+        }
+        if self.max_create_size == 0 {
+            return Err(ConfigError::Invalid("max_create_size must be greater than 0".to_string()));
+        }
+        if self.fetch_timeout == 0 {
+            return Err(ConfigError::Invalid("fetch_timeout must be greater than 0".to_string()));
+        }
+        if self.upload_field.trim().is_empty() {
+            return Err(ConfigError::Invalid("upload_field must not be empty".to_string()));
+        }
+        if self.torrent.max_peers_per_torrent == 0 || self.torrent.max_peers_per_torrent > self.torrent.max_connections {
+            return Err(ConfigError::Invalid(
+                "max_peers_per_torrent must be between 1 and max_connections".to_string()
+            ));
+        }
+        if self.torrent.connect_timeout == 0 || self.torrent.handshake_timeout == 0 {
+            return Err(ConfigError::Invalid("connect_timeout and handshake_timeout must be greater than 0".to_string()));
+        }
+        if self.torrent.user_agent.trim().is_empty() {
+            return Err(ConfigError::Invalid("user_agent must not be empty".to_string()));
+        }
+        for (name, dir) in &[("metainfo_dir", &self.metainfo_dir), ("data_dir", &self.torrent.data_dir)] {
+            fs::create_dir_all(dir).map_err(|e| ConfigError::Invalid(
+                format!("can't create {} {}: {}", name, dir.display(), e)
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Путь к .torrent в каталоге метаинформации; None, если hash - не hex-строка info_hash
+    pub fn metainfo_path(&self, hash: &str) -> Option<PathBuf> {
+        if hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            Some(self.metainfo_dir.join(hash.to_lowercase()))
+        } else {
+            None
+        }
+    }
+}
+
+pub fn load() -> Result<Config, ConfigError> {
+    Config::load(std::env::args().skip(1), std::env::vars())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(ToString::to_string).collect()
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(uuid::Uuid::new_v4().to_string())
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let config: Config = toml::from_str(r#"
+            bind = "0.0.0.0:80"
+            [torrent]
+            peer_port = 7000
+        "#).unwrap();
+        assert_eq!("0.0.0.0:80", config.bind);
+        assert_eq!(7000, config.torrent.peer_port);
+        assert_eq!(50, config.torrent.max_peers_per_torrent);
+        assert!(toml::from_str::<Config>("bnid = \"0.0.0.0:80\"").is_err());
+    }
+
+    #[test]
+    fn test_override_order() {
+        let dir = temp_dir();
+        let file = dir.with_extension("toml");
+        fs::write(&file, format!(
+            "bind = \"0.0.0.0:80\"\nmetainfo_dir = {:?}\n[torrent]\ndata_dir = {:?}\npeer_port = 7000\nupload_rate = 1\n",
+            dir.join("meta"), dir.join("data")
+        )).unwrap();
+        let vars = vec![
+            ("MEDIA_SERVICE_PEER_PORT".to_string(), "7001".to_string()),
+            ("MEDIA_SERVICE_UPLOAD_RATE".to_string(), "2".to_string()),
+        ];
+        let config = Config::load(
+            args(&["--config", file.to_str().unwrap(), "--upload-rate=3"]),
+            vars,
+        ).unwrap();
+        assert_eq!("0.0.0.0:80", config.bind);
+        assert_eq!(7001, config.torrent.peer_port);
+        assert_eq!(3, config.torrent.upload_rate);
+        assert!(dir.join("data").is_dir());
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let dir = temp_dir();
+        let data_dir = format!("--data-dir={}", dir.join("data").display());
+        let metainfo_dir = format!("--metainfo-dir={}", dir.join("meta").display());
+        let load = |extra: &[&str]| {
+            let mut list = args(&[&data_dir, &metainfo_dir]);
+            list.extend(args(extra));
+            Config::load(list, Vec::new())
+        };
+        assert!(load(&[]).is_ok());
+        match load(&["--bind", "localhost"]) {
+            Err(ConfigError::Value { ref key, .. }) if key == "bind" => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match load(&["--peer-port", "70000"]) {
+            Err(ConfigError::Value { ref key, .. }) if key == "peer_port" => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match load(&["--max-peers-per-torrent", "1000"]) {
+            Err(ConfigError::Invalid(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match load(&["--colour", "red"]) {
+            Err(ConfigError::Unknown(ref name)) if name == "--colour" => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match load(&["--bind"]) {
+            Err(ConfigError::MissingValue(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_metainfo_path() {
+        let config = Config::default();
+        assert_eq!(
+            Some(PathBuf::from("torrents/0123456789abcdef0123456789abcdef01234567")),
+            config.metainfo_path("0123456789ABCDEF0123456789abcdef01234567")
+        );
+        assert_eq!(None, config.metainfo_path("../../etc/passwd"));
+    }
+}
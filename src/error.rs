@@ -0,0 +1,226 @@
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::http::{header, StatusCode};
+use std::io;
+use storage::CachedSinkError;
+use torrent::{TorrentError, PeerError, AnnounceResponseError, BitfieldError};
+
+#[derive(Debug, Fail)]
+pub enum ApiError {
+    #[fail(display = "{}", _0)]
+    Cache(CachedSinkError),
+    #[fail(display = "{}", _0)]
+    Torrent(TorrentError),
+    #[fail(display = "{}", _0)]
+    Peer(PeerError),
+    #[fail(display = "{}", _0)]
+    Tracker(AnnounceResponseError),
+    #[fail(display = "{}", _0)]
+    Bitfield(BitfieldError),
+    #[fail(display = "upload exceeds the limit of {} bytes", _0)]
+    TooLarge(usize),
+    #[fail(display = "unsupported content type {}", _0)]
+    UnsupportedMediaType(String),
+    #[fail(display = "invalid .torrent file")]
+    InvalidMetainfo(String),
+    #[fail(display = "{}", _0)]
+    NotFound(String),
+    #[fail(display = "{}", _0)]
+    BadRequest(String),
+    #[fail(display = "{}", _0)]
+    Conflict(String),
+    #[fail(display = "can't fetch .torrent: {}", _0)]
+    Fetch(String),
+    #[fail(display = "url points to a magnet link, only .torrent files are supported")]
+    Magnet(String),
+    #[fail(display = "requested range is outside of {} bytes", _0)]
+    RangeNotSatisfiable(u64),
+    #[fail(display = "{}", _0)]
+    Io(io::Error),
+    #[fail(display = "{}", message)]
+    Http { status: StatusCode, message: String },
+    #[fail(display = "{}", _0)]
+    Internal(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Cache(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Torrent(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Peer(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Tracker(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Bitfield(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::InvalidMetainfo(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Fetch(_) => StatusCode::BAD_GATEWAY,
+            ApiError::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            ApiError::Magnet(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Io(e) if e.kind() == io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Http { status, .. } => *status,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Cache(_) => "upload_too_large",
+            ApiError::Torrent(_) => "torrent_error",
+            ApiError::Peer(_) => "peer_error",
+            ApiError::Tracker(_) => "tracker_error",
+            ApiError::Bitfield(_) => "bitfield_error",
+            ApiError::TooLarge(_) => "upload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::InvalidMetainfo(_) => "invalid_metainfo",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Fetch(_) => "fetch_failed",
+            ApiError::RangeNotSatisfiable(_) => "range_not_satisfiable",
+            ApiError::Magnet(_) => "magnet_link",
+            ApiError::Io(e) if e.kind() == io::ErrorKind::NotFound => "not_found",
+            ApiError::Io(_) => "io_error",
+            //код по статусу: 500 с "bad_request" сбивает клиентов с толку
+            ApiError::Http { status, .. } => match *status {
+                StatusCode::PAYLOAD_TOO_LARGE => "upload_too_large",
+                StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+                StatusCode::NOT_FOUND => "not_found",
+                status if status.is_server_error() => "internal",
+                _ => "bad_request",
+            },
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    fn details(&self) -> Option<String> {
+        match self {
+            ApiError::InvalidMetainfo(details) => Some(details.clone()),
+            ApiError::Magnet(uri) => Some(uri.clone()),
+            ApiError::Io(e) => Some(format!("{:?}", e.kind())),
+            _ => None,
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.code().to_string(),
+            message: self.to_string(),
+            details: self.details(),
+        }
+    }
+
+    pub fn not_found<T: ToString>(message: T) -> Self {
+        ApiError::NotFound(message.to_string())
+    }
+
+    pub fn bad_request<T: ToString>(message: T) -> Self {
+        ApiError::BadRequest(message.to_string())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status());
+        if let ApiError::RangeNotSatisfiable(size) = self {
+            response.header(header::CONTENT_RANGE, format!("bytes */{}", size));
+        }
+        response.json(self.body())
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        ApiError::Io(e)
+    }
+}
+
+impl From<TorrentError> for ApiError {
+    fn from(e: TorrentError) -> Self {
+        ApiError::Torrent(e)
+    }
+}
+
+/// Ошибки actix (разбор json, multipart, payload) отдаем с их статусом, но в нашем формате
+impl From<actix_web::Error> for ApiError {
+    fn from(e: actix_web::Error) -> Self {
+        let status = e.as_response_error().error_response().status();
+        ApiError::Http { status, message: e.to_string() }
+    }
+}
+
+impl From<failure::Error> for ApiError {
+    fn from(e: failure::Error) -> Self {
+        let e = match e.downcast::<CachedSinkError>() {
+            Ok(e) => return ApiError::Cache(e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<TorrentError>() {
+            Ok(e) => return ApiError::Torrent(e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<PeerError>() {
+            Ok(e) => return ApiError::Peer(e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<AnnounceResponseError>() {
+            Ok(e) => return ApiError::Tracker(e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<BitfieldError>() {
+            Ok(e) => return ApiError::Bitfield(e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<io::Error>() {
+            Ok(e) => return ApiError::Io(e),
+            Err(e) => e,
+        };
+        ApiError::Internal(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status_mapping() {
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, ApiError::from(failure::Error::from(CachedSinkError)).status());
+        assert_eq!(StatusCode::BAD_GATEWAY, ApiError::from(failure::Error::from(AnnounceResponseError::Invalid)).status());
+        assert_eq!(StatusCode::BAD_GATEWAY, ApiError::from(failure::Error::from(PeerError::Timeout("handshake"))).status());
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, ApiError::from(failure::Error::from(TorrentError("x".to_string()))).status());
+        let not_found = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        assert_eq!(StatusCode::NOT_FOUND, ApiError::from(failure::Error::from(not_found)).status());
+    }
+
+    #[test]
+    fn test_body() {
+        assert_eq!(
+            ErrorBody {
+                code: "tracker_error".to_string(),
+                message: "received error message from tracker: banned".to_string(),
+                details: None,
+            },
+            ApiError::Tracker(AnnounceResponseError::FailureMessage("banned".to_string())).body()
+        );
+        let http = |status| ApiError::Http { status, message: "x".to_string() }.code();
+        assert_eq!("bad_request", http(StatusCode::BAD_REQUEST));
+        assert_eq!("upload_too_large", http(StatusCode::PAYLOAD_TOO_LARGE));
+        assert_eq!("internal", http(StatusCode::INTERNAL_SERVER_ERROR));
+        assert_eq!("internal", http(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(
+            Some("bad bencode".to_string()),
+            ApiError::InvalidMetainfo("bad bencode".to_string()).body().details
+        );
+    }
+}
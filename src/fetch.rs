@@ -0,0 +1,159 @@
+extern crate tokio;
+extern crate url;
+
+use actix_web::{client, HttpMessage};
+use actix_web::error::PayloadError;
+use actix_web::http::header;
+use bytes::Bytes;
+use error::ApiError;
+use futures::Future;
+use self::tokio::timer::Timeout;
+use self::url::Url;
+use std::time::Duration;
+
+const MAX_REDIRECTS: usize = 5;
+
+type FetchFuture = Box<Future<Item=Bytes, Error=ApiError>>;
+
+fn failed(e: ApiError) -> FetchFuture {
+    Box::new(futures::failed(e))
+}
+
+/// Скачивает .torrent по http(s), проходя по редиректам. Редирект на magnet: - отдельная ошибка,
+/// метаданные по magnet-ссылке мы получать не умеем.
+pub fn fetch(url: &str, limit: usize, timeout: Duration) -> FetchFuture {
+    let request = fetch_with_redirects(url.to_string(), limit, timeout, MAX_REDIRECTS);
+    Box::new(Timeout::new(request, timeout).map_err(move |e| {
+        if e.is_elapsed() {
+            ApiError::Fetch(format!("no response in {} seconds", timeout.as_secs()))
+        } else {
+            e.into_inner().unwrap_or_else(|| ApiError::Internal("timer failure".to_string()))
+        }
+    }))
+}
+
+fn fetch_with_redirects(url: String, limit: usize, timeout: Duration, redirects_left: usize) -> FetchFuture {
+    let parsed = match Url::parse(&url) {
+        Ok(parsed) => parsed,
+        Err(e) => return failed(ApiError::bad_request(format!("invalid url {}: {}", url, e))),
+    };
+    match parsed.scheme() {
+        "http" | "https" => {}
+        "magnet" => return failed(ApiError::Magnet(url)),
+        scheme => return failed(ApiError::bad_request(format!("unsupported url scheme {}", scheme))),
+    }
+    let request = match client::get(parsed.as_str()).finish() {
+        Ok(request) => request,
+        Err(e) => return failed(e.into()),
+    };
+    Box::new(request.send()
+        .timeout(timeout)
+        .map_err(|e| ApiError::Fetch(e.to_string()))
+        .and_then(move |response| -> FetchFuture {
+            let status = response.status();
+            if status.is_redirection() {
+                let location = match response.headers().get(header::LOCATION).and_then(|l| l.to_str().ok()) {
+                    Some(location) => location,
+                    None => return failed(ApiError::Fetch(format!("{} without Location header", status))),
+                };
+                if redirects_left == 0 {
+                    return failed(ApiError::Fetch("too many redirects".to_string()));
+                }
+                let next = match parsed.join(location) {
+                    Ok(next) => next.into_string(),
+                    Err(_) => location.to_string(),
+                };
+                return fetch_with_redirects(next, limit, timeout, redirects_left - 1);
+            }
+            if !status.is_success() {
+                return failed(ApiError::Fetch(format!("server responded with {}", status)));
+            }
+            let length = response.headers().get(header::CONTENT_LENGTH)
+                .and_then(|l| l.to_str().ok())
+                .and_then(|l| l.parse::<usize>().ok());
+            if length.map(|l| l > limit).unwrap_or(false) {
+                return failed(ApiError::TooLarge(limit));
+            }
+            Box::new(response.body().limit(limit).map_err(move |e| match e {
+                PayloadError::Overflow => ApiError::TooLarge(limit),
+                e => ApiError::Fetch(e.to_string()),
+            }))
+        }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::actix;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Подставной http-сервер: на каждое соединение отдает следующий ответ из списка
+    fn serve(responses: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(&response).unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn redirect(location: &str) -> Vec<u8> {
+        format!("HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", location).into_bytes()
+    }
+
+    fn ok(body: &[u8]) -> Vec<u8> {
+        let mut ret = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-bittorrent\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        ).into_bytes();
+        ret.extend_from_slice(body);
+        ret
+    }
+
+    fn run(url: &str, limit: usize) -> Result<Bytes, ApiError> {
+        let mut sys = actix::System::new("fetch-test");
+        sys.block_on(fetch(url, limit, Duration::from_secs(5)))
+    }
+
+    #[test]
+    fn test_follow_redirects() {
+        let body = b"d8:announce3:urle";
+        let base = serve(vec![redirect("/next"), redirect("/x.torrent"), ok(body)]);
+        assert_eq!(Bytes::from(body.as_ref()), run(&format!("{}/a", base), 1024).unwrap());
+    }
+
+    #[test]
+    fn test_magnet_redirect() {
+        let base = serve(vec![redirect("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567")]);
+        match run(&format!("{}/a", base), 1024) {
+            Err(ApiError::Magnet(uri)) => assert!(uri.starts_with("magnet:?xt=urn:btih:")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let base = serve(vec![ok(&[b'd'; 100])]);
+        match run(&format!("{}/a", base), 10) {
+            Err(ApiError::TooLarge(10)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match run("ftp://example.com/x.torrent", 10) {
+            Err(ApiError::BadRequest(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
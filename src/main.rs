@@ -6,16 +6,28 @@ extern crate futures;
 extern crate uuid;
 extern crate hex;
 extern crate failure;
+extern crate sha1;
+extern crate tokio;
 
 #[macro_use] extern crate failure_derive;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate display_derive;
+#[macro_use] extern crate serde_json;
 extern crate core;
 
+mod bencode;
 mod request_utils;
 mod storage;
 mod response;
 mod torrent;
+mod media;
+mod playlist;
+mod config;
+mod error;
+mod fetch;
+mod metrics;
+#[cfg(test)]
+mod swarm;
 
 use actix_web::{
     server,
@@ -28,78 +40,601 @@ use actix_web::{
     http::Method,
 };
 use futures::{Future, Stream};
-use bip_metainfo::MetainfoFile;
-use storage::CachedSink;
+use bytes::Bytes;
+use bip_metainfo::{InfoHash, MetainfoFile};
 use response::TorrentFile;
 use actix_web::Body;
+use actix_web::http::{header, StatusCode};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use config::Config;
+use error::ApiError;
+use metrics::Metrics;
 
+struct AppState {
+    torrents: torrent::Service,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+}
+
+type ApiResponse = FutureResponse<HttpResponse, ApiError>;
+
+fn failed(e: ApiError) -> ApiResponse {
+    Box::new(futures::failed(e))
+}
+
+fn parse_metainfo(bytes: &[u8]) -> Result<MetainfoFile, ApiError> {
+    MetainfoFile::from_bytes(bytes).map_err(|e| ApiError::InvalidMetainfo(e.to_string()))
+}
+
+/// .torrent из каталога вместе с исходными байтами
+fn read_torrent(req: &HttpRequest<AppState>, hash: &str) -> impl Future<Item=(MetainfoFile, Bytes), Error=ApiError> {
+    let path = req.state().config.metainfo_path(hash)
+        .ok_or_else(|| ApiError::not_found("torrent not found"));
+    futures::future::result(path)
+        .and_then(|path| storage::read(path).from_err())
+        .and_then(|bytes| Ok((parse_metainfo(&bytes)?, bytes)))
+}
+
+fn read_metainfo(req: &HttpRequest<AppState>, hash: &str) -> impl Future<Item=MetainfoFile, Error=ApiError> {
+    read_torrent(req, hash).map(|(meta, _)| meta)
+}
+
+/// ?view=tree - добавить к плоскому списку файлов дерево каталогов
+fn tree_view<S>(req: &HttpRequest<S>) -> bool {
+    req.query().get("view").map(|v| v == "tree").unwrap_or(false)
+}
+
+/// Проверенный .torrent кладем в каталог метаинформации и запускаем, в ответ - его описание
+fn catalog(config: &Config, torrents: &torrent::Service, bytes: &[u8]) -> Result<HttpResponse, ApiError> {
+    let metainfo = parse_metainfo(bytes)?;
+    storage::store(&config.metainfo_dir.join(hex::encode(metainfo.info_hash())), bytes)?;
+    let response = TorrentFile::new(&metainfo, bytes);
+    torrent::add(torrents, metainfo);
+    Ok(HttpResponse::Ok().json(response))
+}
 
-fn index(_req: &HttpRequest) -> impl Responder {
-    HttpResponse::Ok().body(r#"<html>
-        <head><title>Upload Test</title></head>
-        <body>
-            <form action="/torrent" method="POST" enctype="multipart/form-data">
-                <input type="file" name="file"/>
-                <input type="submit" value="Submit"></button>
-            </form>
-        </body>
-    </html>"#
-    )
-}
-
-
-fn upload_torrent(req: HttpRequest) -> FutureResponse<HttpResponse> {
-    match request_utils::invoke_body_size(&req) {
-        Err(err) => futures::failed(err).responder(),
-        Ok(size) => {
-            use uuid::Uuid;
-            let file_name = Uuid::new_v4().to_string();
-            request_utils::invoke_request_data(&req)
-                .forward(
-                    CachedSink::new(
-                        storage::make_writer(file_name.to_string()), //to_string - неявный clone
-                        size,
-                    )
-                )
-                .and_then(move |(_, sink)| {
-                    let bytes = sink.as_ref();
-                    let metainfo = MetainfoFile::from_bytes(bytes).unwrap();
-                    let response = TorrentFile::from(&metainfo);
-                    match serde_json::to_string(&response) {
-                        Ok(body) => Ok(HttpResponse::Ok().body(body).into()),
-                        Err(_e) => unimplemented!(),
+fn hash_param(req: &HttpRequest<AppState>) -> String {
+    req.match_info().get("hash").unwrap_or_default().to_string()
+}
+
+
+const INDEX_HTML: &str = include_str!("ui/index.html");
+
+/// Встроенный интерфейс: каталог, загрузка, прогресс и плеер
+fn index(req: &HttpRequest<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(INDEX_HTML.replace("{{upload_field}}", &req.state().config.upload_field))
+}
+
+
+const CHUNKED_UPLOAD_CAPACITY: usize = 64 * 1024;
+
+fn upload_torrent(req: HttpRequest<AppState>) -> ApiResponse {
+    let config = req.state().config.clone();
+    let torrents = req.state().torrents.clone();
+    //в форме к .torrent добавляются границы и заголовки частей: по Content-Length только подбираем буфер,
+    //лимит на байты самого поля проверяет буфер
+    let (data, limit): (Box<Stream<Item=Bytes, Error=actix_web::Error>>, _) = match req.content_type() {
+        "multipart/form-data" => (Box::new(request_utils::invoke_request_data(&req, &config.upload_field)), usize::max_value()),
+        "application/x-bittorrent" => (Box::new(request_utils::invoke_raw_data(&req)), config.max_upload_size),
+        other => return failed(ApiError::UnsupportedMediaType(other.to_string())),
+    };
+    let capacity = match request_utils::invoke_body_size(&req, limit) {
+        Ok(size) => size.unwrap_or(CHUNKED_UPLOAD_CAPACITY),
+        Err(err) => return failed(err),
+    };
+    let mut first = true;
+    data
+        .map_err(ApiError::from)
+        .and_then(move |chunk| {
+            //не тратим память на то, что заведомо не bencode-словарь
+            if first && !chunk.is_empty() {
+                first = false;
+                if chunk[0] != b'd' {
+                    return Err(ApiError::InvalidMetainfo("payload is not bencoded".to_string()));
+                }
+            }
+            Ok(chunk)
+        })
+        .forward(storage::make_buffer(capacity, config.max_upload_size))
+        .and_then(move |(_, buffer)| {
+            if buffer.as_ref().is_empty() {
+                return Err(ApiError::bad_request(format!("no .torrent in request (multipart field {})", config.upload_field)));
+            }
+            catalog(&config, &torrents, buffer.as_ref())
+        })
+        .responder()
+}
+
+#[derive(Deserialize)]
+struct FetchRequest {
+    url: String,
+}
+
+fn fetch_torrent(req: HttpRequest<AppState>) -> ApiResponse {
+    let config = req.state().config.clone();
+    let torrents = req.state().torrents.clone();
+    let request: FutureResponse<FetchRequest, ApiError> = match req.content_type() {
+        "application/json" => Box::new(req.json().map_err(ApiError::bad_request)),
+        "application/x-www-form-urlencoded" => Box::new(req.urlencoded().map_err(ApiError::bad_request)),
+        other => return failed(ApiError::UnsupportedMediaType(other.to_string())),
+    };
+    request
+        .and_then(move |request| {
+            let timeout = Duration::from_secs(config.fetch_timeout);
+            fetch::fetch(request.url.trim(), config.max_upload_size, timeout)
+                .and_then(move |bytes| {
+                    if !bytes.starts_with(b"d") {
+                        return Err(ApiError::InvalidMetainfo("payload is not bencoded".to_string()));
                     }
-                }).responder()
+                    catalog(&config, &torrents, &bytes)
+                })
+        })
+        .responder()
+}
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    path: String,
+    piece_length: Option<usize>,
+    #[serde(default)]
+    trackers: Vec<String>,
+    comment: Option<String>,
+    #[serde(default)]
+    private: bool,
+}
+
+impl CreateRequest {
+    fn options(&self) -> torrent::CreateOptions {
+        torrent::CreateOptions {
+            piece_length: self.piece_length,
+            trackers: self.trackers.clone(),
+            comment: self.comment.clone(),
+            private: self.private,
         }
     }
 }
 
-fn download(req: HttpRequest) -> FutureResponse<HttpResponse> {
-    let hash = req.query().get("hash").unwrap().to_string();
+/// Имя файла или каталога прямо внутри каталога с данными
+fn data_path(config: &Config, name: &str) -> Result<PathBuf, ApiError> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(config.torrent.data_dir.join(name)),
+        _ => Err(ApiError::bad_request("path must be a name inside the data directory")),
+    }
+}
+
+fn form_options(form: &request_utils::UploadForm) -> Result<torrent::CreateOptions, ApiError> {
+    let field = |name: &str| form.fields.get(name).map(|v| v.trim()).filter(|v| !v.is_empty());
+    Ok(torrent::CreateOptions {
+        piece_length: match field("piece_length") {
+            Some(v) => Some(v.parse().map_err(|_| ApiError::bad_request("piece_length must be a number"))?),
+            None => None,
+        },
+        trackers: field("trackers")
+            .map(|v| v.lines().map(str::trim).filter(|t| !t.is_empty()).map(ToString::to_string).collect())
+            .unwrap_or_default(),
+        comment: field("comment").map(ToString::to_string),
+        private: field("private").map(|v| v == "1" || v == "true" || v == "on").unwrap_or(false),
+    })
+}
+
+/// Переносит загруженные файлы из временного каталога туда, откуда их будет раздавать торрент
+fn place_upload(config: &Config, staging: &Path, form: &request_utils::UploadForm) -> Result<PathBuf, ApiError> {
+    let name = form.fields.get("name").map(|n| n.trim()).filter(|n| !n.is_empty());
+    let (from, to) = match (form.files.len(), name) {
+        (0, _) => return Err(ApiError::bad_request("no files uploaded")),
+        (1, None) => {
+            let file = &form.files[0];
+            let file_name = file.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            (file.clone(), data_path(config, &file_name)?)
+        }
+        (_, Some(name)) => (staging.to_path_buf(), data_path(config, name)?),
+        (_, None) => return Err(ApiError::bad_request("name is required for several files")),
+    };
+    if to.exists() {
+        return Err(ApiError::Conflict("content with this name already exists".to_string()));
+    }
+    std::fs::rename(&from, &to)?;
+    if staging.exists() {
+        std::fs::remove_dir_all(staging)?;
+    }
+    Ok(to)
+}
+
+fn create_torrent(req: HttpRequest<AppState>) -> ApiResponse {
+    let torrents = req.state().torrents.clone();
+    let config = req.state().config.clone();
+    let source: FutureResponse<(PathBuf, torrent::CreateOptions), ApiError> = if req.content_type() == "application/json" {
+        let config = config.clone();
+        req.json()
+            .map_err(ApiError::bad_request)
+            .and_then(move |request: CreateRequest| {
+                let source = data_path(&config, &request.path)?;
+                if !source.exists() {
+                    return Err(ApiError::not_found("source not found in the data directory"));
+                }
+                Ok((source, request.options()))
+            })
+            .responder()
+    } else {
+        let config = config.clone();
+        let staging = config.torrent.data_dir.join(format!(".upload-{}", uuid::Uuid::new_v4()));
+        let cleanup = staging.clone();
+        request_utils::save_multipart(&req, staging.clone(), config.max_create_size)
+            .from_err()
+            .and_then(move |form| {
+                let options = form_options(&form)?;
+                Ok((place_upload(&config, &staging, &form)?, options))
+            })
+            //загруженное, но не перенесенное никому не нужно
+            .map_err(move |e| {
+                if cleanup.exists() {
+                    std::fs::remove_dir_all(&cleanup).ok();
+                }
+                e
+            })
+            .responder()
+    };
+    source
+        .and_then(|(source, options)| torrent::create(source, options).from_err())
+        .and_then(move |bytes| catalog(&config, &torrents, &bytes))
+        .responder()
+}
+
+fn download(req: HttpRequest<AppState>) -> ApiResponse {
+    let hash = match req.query().get("hash") {
+        Some(hash) => hash.to_string(),
+        None => return failed(ApiError::bad_request("hash query parameter is required")),
+    };
     use torrent::*;
-    storage::read(hash)
-        .from_err()
-        .map(|bytes| MetainfoFile::from_bytes(bytes).unwrap()) //result -to future
+    let started = Instant::now();
+    let metrics = req.state().metrics.clone();
+    read_metainfo(&req, &hash)
         .and_then(move |meta | {
-            let mut client = torrent::new_client(meta);
-            let body = Box::new(client.download().from_err());
+            let path = match meta.info().directory() {
+                Some(dir) => dir.to_path_buf(),
+                None => meta.info().files().next().map(|f| f.path().to_path_buf()).unwrap_or_default(),
+            };
+            let size = meta.info().files().map(|f| f.length()).sum();
+            let range = match request_utils::invoke_range(&req, size) {
+                Ok(range) => range,
+                Err(e) => return failed(e),
+            };
+            let mut client = torrent::new_client(&req.state().torrents, meta);
+            let stream = match range {
+                Some(range) => client.download_part(range.start, range.length()),
+                None => client.download(),
+            };
+            stream_response(req, stream, path, range.map(|range| (range, size)))
+        })
+        //stream_response готов, когда есть первый кусок данных
+        .map(move |response| {
+            metrics.download_ttfb.observe(started.elapsed());
+            response
+        })
+        .responder()
+}
+
+fn download_file(req: HttpRequest<AppState>) -> ApiResponse {
+    let hash = hash_param(&req);
+    let index: usize = match req.match_info().query("index") {
+        Ok(index) => index,
+        Err(_) => return failed(ApiError::bad_request("file index must be a number")),
+    };
+    use torrent::*;
+    read_metainfo(&req, &hash)
+        .and_then(move |meta| {
+            let (path, size) = match meta.info().files().nth(index) {
+                Some(file) => (file.path().to_path_buf(), file.length()),
+                None => return failed(ApiError::not_found("file not found in torrent")),
+            };
+            let range = match request_utils::invoke_range(&req, size) {
+                Ok(range) => range,
+                Err(e) => return failed(e),
+            };
+            let mut client = torrent::new_client(&req.state().torrents, meta);
+            let stream = match range {
+                Some(range) => client.download_file_range(index, range.start, range.length()),
+                None => client.download_file(index),
+            };
+            stream_response(req, stream, path, range.map(|range| (range, size)))
+        })
+        .responder()
+}
+
+/// Каталог: все .torrent из каталога метаинформации; нечитаемые файлы пропускаются
+fn list_torrents(req: HttpRequest<AppState>) -> ApiResponse {
+    let config = req.state().config.clone();
+    let tree = tree_view(&req);
+    let paths: Vec<PathBuf> = match std::fs::read_dir(&config.metainfo_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str().and_then(|name| config.metainfo_path(name)))
+            .collect(),
+        Err(e) => return failed(e.into()),
+    };
+    futures::stream::iter_ok(paths)
+        .and_then(|path| storage::read(path).then(|bytes| Ok::<_, ApiError>(bytes.ok())))
+        .filter_map(|bytes| bytes.and_then(|bytes| {
+            MetainfoFile::from_bytes(&bytes).ok().map(|meta| TorrentFile::new(&meta, &bytes))
+        }))
+        .map(move |torrent| if tree { torrent.with_tree() } else { torrent })
+        .collect()
+        .map(|mut torrents| {
+            torrents.sort_by(|a, b| a.name.cmp(&b.name));
+            HttpResponse::Ok().json(torrents)
+        })
+        .responder()
+}
+
+fn get_torrent(req: HttpRequest<AppState>) -> ApiResponse {
+    let tree = tree_view(&req);
+    read_torrent(&req, &hash_param(&req))
+        .map(move |(meta, bytes)| {
+            let torrent = TorrentFile::new(&meta, &bytes);
+            HttpResponse::Ok().json(if tree { torrent.with_tree() } else { torrent })
+        })
+        .responder()
+}
+
+/// Весь .torrent как json, для разбора битых торрентов; читается без bip_metainfo
+fn get_raw(req: HttpRequest<AppState>) -> ApiResponse {
+    let path = match req.state().config.metainfo_path(&hash_param(&req)) {
+        Some(path) => path,
+        None => return failed(ApiError::not_found("torrent not found")),
+    };
+    storage::read(path)
+        .from_err()
+        .and_then(|bytes| response::raw_metainfo(&bytes)
+            .ok_or_else(|| ApiError::InvalidMetainfo("payload is not bencoded".to_string())))
+        .map(|raw| HttpResponse::Ok().json(raw))
+        .responder()
+}
 
-            Ok(req.build_response(Default::default())
-                .chunked()
-                .body(Body::Streaming(body)).into())
+/// DELETE /torrent/{hash}: убирает из каталога; ?data=1 - вместе со скачанными файлами
+fn delete_torrent(req: HttpRequest<AppState>) -> ApiResponse {
+    let torrents = req.state().torrents.clone();
+    let hash = hash_param(&req);
+    let delete_data = req.query().get("data").map(|v| v == "1" || v == "true").unwrap_or(false);
+    let path = req.state().config.metainfo_path(&hash);
+    read_metainfo(&req, &hash)
+        .and_then(move |meta| torrent::remove(&torrents, meta, delete_data).from_err())
+        .and_then(move |_| {
+            if let Some(path) = path {
+                std::fs::remove_file(path)?;
+            }
+            Ok(HttpResponse::NoContent().finish())
         })
         .responder()
 }
 
+fn get_playlist(req: HttpRequest<AppState>) -> ApiResponse {
+    let hash = hash_param(&req);
+    let all = req.query().get("all").map(|v| v == "1" || v == "true").unwrap_or(false);
+    read_metainfo(&req, &hash)
+        .map(move |meta| {
+            let files: Vec<_> = meta.info().files()
+                .map(|f| f.path().to_path_buf())
+                .enumerate()
+                .collect();
+            let base_url = {
+                let info = req.connection_info();
+                format!("{}://{}", info.scheme(), info.host())
+            };
+            HttpResponse::Ok()
+                .content_type(playlist::CONTENT_TYPE)
+                .body(playlist::build(&base_url, &hash, &files, all))
+        })
+        .responder()
+}
+
+/// GET - узнать лимиты скорости, PUT - поменять; без {hash} - общие
+fn limits(req: HttpRequest<AppState>) -> ApiResponse {
+    let torrents = req.state().torrents.clone();
+    let info_hash: FutureResponse<Option<InfoHash>, ApiError> = match req.match_info().get("hash") {
+        Some(hash) => Box::new(read_metainfo(&req, hash).map(|meta| Some(meta.info_hash()))),
+        None => Box::new(futures::future::ok(None)),
+    };
+    let update: FutureResponse<Option<torrent::LimitsUpdate>, ApiError> = if req.method() == Method::PUT {
+        Box::new(req.json().map(Some).map_err(ApiError::bad_request))
+    } else {
+        Box::new(futures::future::ok(None))
+    };
+    info_hash.join(update)
+        .and_then(move |(info_hash, update)| torrent::limits(&torrents, info_hash, update).from_err())
+        .map(|limits| HttpResponse::Ok().json(limits))
+        .responder()
+}
+
+fn get_stats(req: HttpRequest<AppState>) -> ApiResponse {
+    let torrents = req.state().torrents.clone();
+    read_metainfo(&req, &hash_param(&req))
+        .and_then(move |meta| torrent::stats(&torrents, meta.info_hash()).from_err())
+        .and_then(|stats| stats.ok_or_else(|| ApiError::not_found("torrent is not active")))
+        .map(|stats| HttpResponse::Ok().json(stats))
+        .responder()
+}
+
+fn get_peers(req: HttpRequest<AppState>) -> ApiResponse {
+    let torrents = req.state().torrents.clone();
+    read_metainfo(&req, &hash_param(&req))
+        .and_then(move |meta| torrent::peers(&torrents, meta.info_hash()).from_err())
+        .and_then(|peers| peers.ok_or_else(|| ApiError::not_found("torrent is not active")))
+        .map(|peers| HttpResponse::Ok().json(peers))
+        .responder()
+}
+
+fn get_metrics(req: &HttpRequest<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(metrics::CONTENT_TYPE)
+        .body(req.state().metrics.render())
+}
+
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// События торрента как text/event-stream; комментарии раз в SSE_KEEP_ALIVE не дают прокси закрыть соединение.
+/// Ответ кончается, когда торрент удален
+fn events(req: HttpRequest<AppState>) -> ApiResponse {
+    let torrents = req.state().torrents.clone();
+    read_metainfo(&req, &hash_param(&req))
+        .and_then(move |meta| torrent::subscribe(&torrents, meta.info_hash()).from_err())
+        .and_then(|events| events.ok_or_else(|| ApiError::not_found("torrent is not active")))
+        .map(|events| {
+            let events = events
+                .map(|event| event.to_sse())
+                .map_err(|_| failure::Error::from(torrent::TorrentError("event bus closed".to_string())));
+            let keep_alive = tokio::timer::Interval::new(Instant::now() + SSE_KEEP_ALIVE, SSE_KEEP_ALIVE)
+                .map(|_| Bytes::from_static(b": keep-alive\n\n"))
+                .from_err();
+            HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .header(header::CACHE_CONTROL, "no-cache")
+                .body(Body::Streaming(Box::new(torrent::KeepAlive::new(events, keep_alive).from_err())))
+        })
+        .responder()
+}
+
+/// Заголовки отдаем, только дождавшись первого куска: по нему определяем тип, если не помогло расширение
+/// range - отдаваемый диапазон и полный размер, ответ тогда 206
+fn stream_response(req: HttpRequest<AppState>, stream: torrent::SizedStream, path: PathBuf,
+                   range: Option<(request_utils::ByteRange, u64)>) -> ApiResponse {
+    let size = stream.size() as u64;
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let content_type = media::from_extension(&path);
+    let active = Metrics::stream_started(&req.state().metrics);
+    stream.into_future()
+        .map_err(|(e, _)| ApiError::from(e))
+        .map(move |(first, rest)| {
+            //по середине файла тип не угадать
+            let from_start = range.map(|(range, _)| range.start == 0).unwrap_or(true);
+            let content_type = content_type
+                .or_else(|| first.as_ref().filter(|_| from_start).and_then(|bytes| media::sniff(bytes)))
+                .unwrap_or(media::DEFAULT_TYPE);
+            let body = active.wrap(futures::stream::iter_ok(first).chain(rest));
+            let mut response = match range {
+                Some((range, total)) => {
+                    let mut response = req.build_response(StatusCode::PARTIAL_CONTENT);
+                    response.header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end, total));
+                    response
+                }
+                None => req.build_response(StatusCode::OK),
+            };
+            response
+                .content_type(content_type)
+                .header(header::ACCEPT_RANGES, "bytes")
+                .header(header::CONTENT_DISPOSITION, media::content_disposition(&name))
+                .content_length(size)
+                .body(Body::Streaming(Box::new(body.from_err())))
+        })
+        .responder()
+}
+
+fn app(torrents: torrent::Service, config: Arc<Config>, metrics: Arc<Metrics>) -> App<AppState> {
+    App::with_state(AppState { torrents, config, metrics })
+        .resource("/", |r| r.f(index))
+        .resource("/metrics", |r| r.method(Method::GET).f(get_metrics))
+        .route("/torrent", Method::GET, list_torrents)
+        .route("/torrent", Method::POST, upload_torrent)
+        .route("/torrent/create", Method::POST, create_torrent)
+        .route("/torrent/fetch", Method::POST, fetch_torrent)
+        .route("/torrent/download", Method::GET, download)
+        .route("/limits", Method::GET, limits)
+        .route("/limits", Method::PUT, limits)
+        .route("/torrent/{hash}/limits", Method::GET, limits)
+        .route("/torrent/{hash}/limits", Method::PUT, limits)
+        .route("/torrent/{hash}", Method::GET, get_torrent)
+        .route("/torrent/{hash}", Method::DELETE, delete_torrent)
+        .route("/torrent/{hash}/raw", Method::GET, get_raw)
+        .route("/torrent/{hash}/file/{index}", Method::GET, download_file)
+        .route("/torrent/{hash}/playlist.m3u8", Method::GET, get_playlist)
+        .route("/torrent/{hash}/stats", Method::GET, get_stats)
+        .route("/torrent/{hash}/events", Method::GET, events)
+        .route("/torrent/{hash}/peers", Method::GET, get_peers)
+}
+
+/// Запуск раздачи всего каталога: после рестарта торренты анонсируются и сидируют, не дожидаясь стрима
+fn restore_torrents(config: &Config, torrents: &torrent::Service) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(&config.metainfo_dir)? {
+        let path = match entry.ok().and_then(|entry| entry.file_name().to_str().and_then(|name| config.metainfo_path(name))) {
+            Some(path) => path,
+            None => continue,
+        };
+        let meta = std::fs::read(&path).map_err(ApiError::from).and_then(|bytes| parse_metainfo(&bytes));
+        match meta {
+            Ok(meta) => torrent::add(torrents, meta),
+            Err(e) => eprintln!("media-service: skipping {}: {}", path.display(), e),
+        }
+    }
+    Ok(())
+}
+
 fn main() {
-    server::new(||
-        vec![
-            App::new()
-                .resource("/", |r| r.f(index))
-                .route("/torrent", Method::POST, upload_torrent)
-                .route("/torrent/download", Method::GET, download)
-        ])
-        .bind("127.0.0.1:8088")
-        .unwrap()
+    let config = match config::load() {
+        Ok(config) => Arc::new(config),
+        Err(config::ConfigError::Help(usage)) => {
+            print!("{}", usage);
+            return;
+        }
+        Err(e) => {
+            eprintln!("media-service: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let metrics = Arc::new(Metrics::default());
+    let torrents = torrent::new_service(config.torrent.clone(), metrics.clone());
+    if let Err(e) = restore_torrents(&config, &torrents) {
+        eprintln!("media-service: can't read {}: {}", config.metainfo_dir.display(), e);
+    }
+    let service = torrents.clone();
+    let bind = config.bind.clone();
+    server::new(move || vec![app(torrents.clone(), config.clone(), metrics.clone())])
+        .bind(&bind)
+        .unwrap_or_else(|e| {
+            eprintln!("media-service: can't bind {}: {}", bind, e);
+            std::process::exit(2);
+        })
+        .system_exit()
         .run();
-}
\ No newline at end of file
+    torrent::shutdown(&service).wait().ok();
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestServer;
+
+    #[test]
+    fn test_events_end_on_remove() {
+        let mut config = Config::default();
+        config.metainfo_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        config.torrent.data_dir = config.metainfo_dir.join("data");
+        std::fs::create_dir_all(&config.torrent.data_dir).unwrap();
+        let metainfo_dir = config.metainfo_dir.clone();
+        let config = Arc::new(config);
+        let metrics = Arc::new(Metrics::default());
+        let torrents = torrent::new_service(config.torrent.clone(), metrics.clone());
+        let mut server = TestServer::with_factory(move || app(torrents.clone(), config.clone(), metrics.clone()));
+
+        let bytes = format!("d4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:{}ee", "a".repeat(20));
+        let request = server.post().uri(server.url("/torrent"))
+            .header(header::CONTENT_TYPE, "application/x-bittorrent")
+            .body(bytes)
+            .unwrap();
+        let response = server.execute(request.send()).unwrap();
+        let body = server.execute(response.body()).unwrap();
+        let hash = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["hash"].as_str().unwrap().to_string();
+
+        let request = server.get().uri(server.url(&format!("/torrent/{}/events", hash))).finish().unwrap();
+        let events = server.execute(request.send()).unwrap();
+        assert_eq!(StatusCode::OK, events.status());
+        let request = server.client(Method::DELETE, &format!("/torrent/{}", hash)).finish().unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, server.execute(request.send()).unwrap().status());
+        //без конца потока body() упал бы по таймауту
+        let body = server.execute(events.body()).unwrap();
+        assert!(body.starts_with(b"event: metadata_received\n"));
+        std::fs::remove_dir_all(&metainfo_dir).ok();
+    }
+}
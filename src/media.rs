@@ -0,0 +1,149 @@
+use std::path::Path;
+
+pub const DEFAULT_TYPE: &str = "application/octet-stream";
+
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("mkv", "video/x-matroska"),
+    ("webm", "video/webm"),
+    ("avi", "video/x-msvideo"),
+    ("mov", "video/quicktime"),
+    ("mpg", "video/mpeg"),
+    ("mpeg", "video/mpeg"),
+    ("ts", "video/mp2t"),
+    ("ogv", "video/ogg"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("aac", "audio/aac"),
+    ("flac", "audio/flac"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mka", "audio/x-matroska"),
+    ("srt", "application/x-subrip"),
+    ("vtt", "text/vtt"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("txt", "text/plain"),
+    ("nfo", "text/plain"),
+];
+
+pub fn from_extension(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    EXTENSIONS.iter()
+        .find(|(e, _)| *e == ext)
+        .map(|(_, t)| *t)
+}
+
+pub fn is_media(path: &Path) -> bool {
+    match from_extension(path) {
+        Some(t) => t.starts_with("video/") || t.starts_with("audio/"),
+        None => false,
+    }
+}
+
+/// Определение типа по первым байтам файла
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        Some("video/mp4")
+    } else if data.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+        //у webm и matroska одинаковый EBML-заголовок, отличаются DocType
+        let header = &data[..std::cmp::min(data.len(), 64)];
+        if header.windows(4).any(|w| w == b"webm") {
+            Some("video/webm")
+        } else {
+            Some("video/x-matroska")
+        }
+    } else if data.starts_with(b"ID3") || (data.len() >= 2 && data[0] == 0xff && data[1] & 0xe0 == 0xe0) {
+        Some("audio/mpeg")
+    } else if data.starts_with(b"fLaC") {
+        Some("audio/flac")
+    } else if data.starts_with(b"OggS") {
+        Some("audio/ogg")
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"AVI " {
+        Some("video/x-msvideo")
+    } else {
+        None
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    let name = name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or("");
+    let name: String = name.chars()
+        .map(|c| if c.is_control() || c == '"' { '_' } else { c })
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => "download".to_string(),
+        name => name.to_string(),
+    }
+}
+
+fn is_attr_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b)
+}
+
+/// RFC 5987: ext-value = charset "'" [ language ] "'" value-chars
+fn encode_ext_value(value: &str) -> String {
+    let mut ret = String::from("UTF-8''");
+    for &b in value.as_bytes() {
+        if is_attr_char(b) {
+            ret.push(b as char);
+        } else {
+            ret.push_str(&format!("%{:02X}", b));
+        }
+    }
+    ret
+}
+
+pub fn content_disposition(name: &str) -> String {
+    let name = sanitize(name);
+    if name.is_ascii() {
+        format!("inline; filename=\"{}\"", name)
+    } else {
+        let fallback: String = name.chars()
+            .map(|c| if c.is_ascii() { c } else { '_' })
+            .collect();
+        format!("inline; filename=\"{}\"; filename*={}", fallback, encode_ext_value(&name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extension() {
+        assert_eq!(Some("video/x-matroska"), from_extension(Path::new("Season 1/E01.MKV")));
+        assert_eq!(Some("audio/flac"), from_extension(Path::new("track.flac")));
+        assert_eq!(None, from_extension(Path::new("README")));
+        assert!(is_media(Path::new("E01.mkv")));
+        assert!(!is_media(Path::new("release.nfo")));
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(Some("video/mp4"), sniff(b"\x00\x00\x00\x18ftypmp42\x00\x00\x00\x00"));
+        assert_eq!(Some("video/webm"), sniff(b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81\x01\x42\x82\x84webm"));
+        assert_eq!(Some("video/x-matroska"), sniff(b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81\x01\x42\x82\x88matroska"));
+        assert_eq!(Some("audio/mpeg"), sniff(b"ID3\x04\x00"));
+        assert_eq!(Some("audio/mpeg"), sniff(b"\xff\xfb\x90\x44"));
+        assert_eq!(Some("audio/flac"), sniff(b"fLaC\x00\x00\x00\x22"));
+        assert_eq!(Some("audio/ogg"), sniff(b"OggS\x00\x02"));
+        assert_eq!(Some("video/x-msvideo"), sniff(b"RIFF\x00\x00\x00\x00AVI LIST"));
+        assert_eq!(None, sniff(b"d8:announce"));
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!("inline; filename=\"movie.mkv\"", content_disposition("dir/movie.mkv"));
+        assert_eq!("inline; filename=\"a_b.mp4\"", content_disposition("a\"b.mp4"));
+        assert_eq!(
+            "inline; filename=\"_____.mp3\"; filename*=UTF-8''%D0%BF%D0%B5%D1%81%D0%BD%D1%8F.mp3",
+            content_disposition("песня.mp3")
+        );
+        assert_eq!("inline; filename=\"download\"", content_disposition("../"));
+    }
+}
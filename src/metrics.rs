@@ -0,0 +1,221 @@
+use futures::{Poll, Stream};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn dec(&self) {
+        self.sub(1);
+    }
+    pub fn sub(&self, n: i64) {
+        self.0.fetch_sub(n, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Гистограмма в секундах; счетчики корзин хранятся не накопленными, суммируются при выводе
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_micros()) / 1e6;
+        if let Some(index) = self.bounds.iter().position(|&bound| seconds <= bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        let micros = duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros());
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Metrics {
+    pub active_torrents: Gauge,
+    pub connected_peers: Gauge,
+    pub bytes_in: Counter, //от пиров, включая отброшенные куски
+    pub bytes_out: Counter,
+    pub hash_failures: Counter,
+    pub tracker_latency: Histogram,
+    pub tracker_errors: Counter,
+    pub active_streams: Gauge,
+    pub download_ttfb: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            active_torrents: Gauge::default(),
+            connected_peers: Gauge::default(),
+            bytes_in: Counter::default(),
+            bytes_out: Counter::default(),
+            hash_failures: Counter::default(),
+            tracker_latency: Histogram::new(LATENCY_BUCKETS),
+            tracker_errors: Counter::default(),
+            active_streams: Gauge::default(),
+            download_ttfb: Histogram::new(LATENCY_BUCKETS),
+        }
+    }
+}
+
+/// Живет, пока отдается тело ответа: держит счетчик активных стримов
+pub struct ActiveStream(Arc<Metrics>);
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.active_streams.dec();
+    }
+}
+
+impl ActiveStream {
+    /// Счетчик держит само тело: он уменьшится, когда тело отдано или брошено
+    pub fn wrap<S: Stream>(self, stream: S) -> CountedStream<S> {
+        CountedStream { stream, _active: self }
+    }
+}
+
+pub struct CountedStream<S> {
+    stream: S,
+    _active: ActiveStream,
+}
+
+impl<S: Stream> Stream for CountedStream<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        self.stream.poll()
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP media_service_{} {}", name, help).ok();
+    writeln!(out, "# TYPE media_service_{} {}", name, kind).ok();
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    header(out, name, "histogram", help);
+    let mut cumulative = 0;
+    for (bound, bucket) in histogram.bounds.iter().zip(&histogram.buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        writeln!(out, "media_service_{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).ok();
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    writeln!(out, "media_service_{}_bucket{{le=\"+Inf\"}} {}", name, count).ok();
+    let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    writeln!(out, "media_service_{}_sum {}", name, sum).ok();
+    writeln!(out, "media_service_{}_count {}", name, count).ok();
+}
+
+impl Metrics {
+    pub fn stream_started(metrics: &Arc<Metrics>) -> ActiveStream {
+        metrics.active_streams.inc();
+        ActiveStream(metrics.clone())
+    }
+
+    /// Текстовый формат Prometheus
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        header(&mut out, "active_torrents", "gauge", "Torrents running in the torrent service.");
+        writeln!(out, "media_service_active_torrents {}", self.active_torrents.get()).ok();
+        header(&mut out, "connected_peers", "gauge", "Peer connections across all torrents.");
+        writeln!(out, "media_service_connected_peers {}", self.connected_peers.get()).ok();
+        header(&mut out, "peer_bytes_total", "counter", "Bytes exchanged with peers.");
+        writeln!(out, "media_service_peer_bytes_total{{direction=\"in\"}} {}", self.bytes_in.get()).ok();
+        writeln!(out, "media_service_peer_bytes_total{{direction=\"out\"}} {}", self.bytes_out.get()).ok();
+        header(&mut out, "hash_failures_total", "counter", "Pieces that failed the SHA-1 check.");
+        writeln!(out, "media_service_hash_failures_total {}", self.hash_failures.get()).ok();
+        histogram(&mut out, "tracker_announce_seconds", "Tracker announce latency.", &self.tracker_latency);
+        header(&mut out, "tracker_errors_total", "counter", "Failed tracker announces.");
+        writeln!(out, "media_service_tracker_errors_total {}", self.tracker_errors.get()).ok();
+        header(&mut out, "active_streams", "gauge", "HTTP responses streaming torrent content.");
+        writeln!(out, "media_service_active_streams {}", self.active_streams.get()).ok();
+        histogram(&mut out, "download_ttfb_seconds", "Time to first byte of /torrent/download.", &self.download_ttfb);
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn test_render() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.active_torrents.set(2);
+        metrics.bytes_in.add(100);
+        metrics.download_ttfb.observe(Duration::from_millis(70));
+        metrics.download_ttfb.observe(Duration::from_millis(300));
+        metrics.download_ttfb.observe(Duration::from_secs(60));
+        {
+            let _stream = Metrics::stream_started(&metrics);
+            assert_eq!(1, metrics.active_streams.get());
+        }
+        assert_eq!(0, metrics.active_streams.get());
+        let body = Metrics::stream_started(&metrics).wrap(futures::stream::iter_ok::<_, ()>(vec![1, 2]));
+        assert_eq!(1, metrics.active_streams.get());
+        assert_eq!(vec![1, 2], body.collect().wait().unwrap());
+        assert_eq!(0, metrics.active_streams.get());
+        let text = metrics.render();
+        for line in &[
+            "# TYPE media_service_active_torrents gauge",
+            "media_service_active_torrents 2",
+            "media_service_peer_bytes_total{direction=\"in\"} 100",
+            "media_service_peer_bytes_total{direction=\"out\"} 0",
+            "media_service_download_ttfb_seconds_bucket{le=\"0.05\"} 0",
+            "media_service_download_ttfb_seconds_bucket{le=\"0.1\"} 1",
+            "media_service_download_ttfb_seconds_bucket{le=\"0.5\"} 2",
+            "media_service_download_ttfb_seconds_bucket{le=\"30\"} 2",
+            "media_service_download_ttfb_seconds_bucket{le=\"+Inf\"} 3",
+            "media_service_download_ttfb_seconds_sum 60.37",
+            "media_service_download_ttfb_seconds_count 3",
+            "media_service_tracker_announce_seconds_count 0",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::cmp::Ordering;
+use std::path::Path;
+use media;
+
+pub const CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+
+#[derive(Debug, PartialEq)]
+enum Chunk<'a> {
+    Text(&'a str),
+    Number(&'a str),
+}
+
+fn chunks(s: &str) -> Vec<Chunk> {
+    let mut ret = Vec::new();
+    let mut start = 0;
+    let mut digits = None;
+    for (i, c) in s.char_indices() {
+        let is_digit = c.is_ascii_digit();
+        if digits != Some(is_digit) {
+            if i > start {
+                ret.push(if digits == Some(true) { Chunk::Number(&s[start..i]) } else { Chunk::Text(&s[start..i]) });
+            }
+            start = i;
+            digits = Some(is_digit);
+        }
+    }
+    if start < s.len() {
+        ret.push(if digits == Some(true) { Chunk::Number(&s[start..]) } else { Chunk::Text(&s[start..]) });
+    }
+    ret
+}
+
+fn compare_numbers(a: &str, b: &str) -> Ordering {
+    let a_trimmed = a.trim_start_matches('0');
+    let b_trimmed = b.trim_start_matches('0');
+    a_trimmed.len().cmp(&b_trimmed.len())
+        .then_with(|| a_trimmed.cmp(b_trimmed))
+        .then_with(|| a.len().cmp(&b.len()))
+}
+
+/// "Натуральная" сортировка: E2 < E10
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let a_chunks = chunks(a);
+    let b_chunks = chunks(b);
+    for (x, y) in a_chunks.iter().zip(b_chunks.iter()) {
+        let ord = match (x, y) {
+            (Chunk::Number(x), Chunk::Number(y)) => compare_numbers(x, y),
+            (Chunk::Number(_), Chunk::Text(_)) => Ordering::Less,
+            (Chunk::Text(_), Chunk::Number(_)) => Ordering::Greater,
+            (Chunk::Text(x), Chunk::Text(y)) => x.to_lowercase().cmp(&y.to_lowercase()),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    a_chunks.len().cmp(&b_chunks.len()).then_with(|| a.cmp(b))
+}
+
+/// files: пары (номер файла в торренте, путь)
+pub fn build<P: AsRef<Path>>(base_url: &str, hash: &str, files: &[(usize, P)], all: bool) -> String {
+    let mut entries: Vec<(usize, String, &Path)> = files.iter()
+        .map(|(index, path)| (*index, path.as_ref().to_string_lossy().into_owned(), path.as_ref()))
+        .filter(|(_, _, path)| all || media::is_media(path))
+        .collect();
+    entries.sort_by(|a, b| natural_cmp(&a.1, &b.1));
+
+    let mut ret = String::from("#EXTM3U\n");
+    for (index, name, path) in entries {
+        let title = path.file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or(name);
+        let title = title.replace(|c: char| c == '\r' || c == '\n', " ");
+        ret.push_str(&format!("#EXTINF:-1,{}\n", title));
+        ret.push_str(&format!("{}/torrent/{}/file/{}\n", base_url.trim_end_matches('/'), hash, index));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec!["E10.mkv", "E2a.mkv", "e2.mkv", "Extras", "E1.mkv"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(vec!["E1.mkv", "e2.mkv", "E2a.mkv", "E10.mkv", "Extras"], names);
+    }
+
+    #[test]
+    fn test_build() {
+        let files = vec![
+            (0, "Show/Show.S01E10.mkv"),
+            (1, "Show/release.nfo"),
+            (2, "Show/Show.S01E02.mkv"),
+            (3, "Show/notes.txt"),
+        ];
+        assert_eq!(
+            "#EXTM3U\n\
+             #EXTINF:-1,Show.S01E02\n\
+             http://host:8088/torrent/abcd/file/2\n\
+             #EXTINF:-1,Show.S01E10\n\
+             http://host:8088/torrent/abcd/file/0\n",
+            build("http://host:8088/", "abcd", &files, false)
+        );
+        assert_eq!(4, build("http://host:8088", "abcd", &files, true).matches("#EXTINF").count());
+    }
+}
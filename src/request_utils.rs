@@ -1,32 +1,390 @@
 extern crate http;
 
-use futures::Stream;
+use futures::{Future, Stream, Sink};
 use actix_web::{error, HttpRequest, HttpMessage, Error};
 use actix_web::multipart::{MultipartItem, Multipart};
 use bytes::Bytes;
 use self::http::header;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use storage;
+use error::ApiError;
+use actix_web::multipart::Field;
+use actix_web::error::MultipartError;
 
 
-pub fn invoke_body_size<M: HttpMessage>(m: &M) -> Result<usize, Error> {
-    match m.headers().get(header::CONTENT_LENGTH) {
-        None => return Err(error::ErrorBadRequest("Content length must be set")),
+/// None - размер заранее неизвестен (chunked transfer encoding)
+pub fn invoke_body_size<M: HttpMessage>(m: &M, limit: usize) -> Result<Option<usize>, ApiError> {
+    let size: usize = match m.headers().get(header::CONTENT_LENGTH) {
+        None => return Ok(None),
         Some(h) => h,
-    }.to_str()
-        .map_err(error::ErrorBadRequest)
-        .and_then(|x| x.parse().map_err(error::ErrorBadRequest))
+    }.to_str().ok()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| ApiError::bad_request("invalid Content-Length"))?;
+    if size > limit {
+        return Err(ApiError::TooLarge(limit));
+    }
+    Ok(Some(size))
 }
 
-fn read_multipart<S>(multipart: Multipart<S>) -> impl Stream<Item=Bytes, Error=Error>
-    where S: Stream<Item=Bytes, Error=error::PayloadError> {
-    multipart
-        .filter_map(|item| match item {
-            MultipartItem::Field(f) => Some(f),
-            MultipartItem::Nested(_) => unimplemented!("nested multipart"),
+/// Диапазон байт из заголовка Range, границы включительно
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Поддерживается один диапазон; несколько диапазонов или непонятный заголовок - отдаем все целиком
+fn parse_range(value: &str, size: u64) -> Result<Option<ByteRange>, ApiError> {
+    let spec = match value.trim().splitn(2, '=').collect::<Vec<_>>().as_slice() {
+        [unit, spec] if unit.trim() == "bytes" && !spec.contains(',') => spec.trim().to_string(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.find('-') {
+        Some(pos) => (spec[..pos].trim(), spec[pos + 1..].trim()),
+        None => return Ok(None),
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, std::cmp::min(end, size.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        (Err(_), Ok(0)) if start.is_empty() => return Err(ApiError::RangeNotSatisfiable(size)),
+        _ => return Ok(None),
+    };
+    if range.0 >= size {
+        return Err(ApiError::RangeNotSatisfiable(size));
+    }
+    Ok(Some(ByteRange { start: range.0, end: range.1 }))
+}
+
+pub fn invoke_range<M: HttpMessage>(m: &M, size: u64) -> Result<Option<ByteRange>, ApiError> {
+    match m.headers().get(header::RANGE).and_then(|h| h.to_str().ok()) {
+        Some(value) => parse_range(value, size),
+        None => Ok(None),
+    }
+}
+
+/// Поля формы с именем name, в том числе из вложенных multipart (у вложенных частей имени может не быть)
+fn select_fields<S>(multipart: Multipart<S>, name: Rc<String>, nested: bool) -> Box<Stream<Item=Field<S>, Error=MultipartError>>
+    where S: Stream<Item=Bytes, Error=error::PayloadError> + 'static {
+    Box::new(multipart
+        .map(move |item| -> Box<Stream<Item=Field<S>, Error=MultipartError>> {
+            match item {
+                MultipartItem::Field(field) => {
+                    let field_name = field.content_disposition()
+                        .and_then(|d| d.get_name().map(ToString::to_string));
+                    let selected = match field_name {
+                        Some(field_name) => field_name == *name,
+                        None => nested,
+                    };
+                    if selected {
+                        Box::new(futures::stream::once(Ok(field)))
+                    } else {
+                        Box::new(futures::stream::empty())
+                    }
+                }
+                MultipartItem::Nested(inner) => select_fields(inner, name.clone(), true),
+            }
         })
+        .flatten())
+}
+
+fn read_multipart<S>(multipart: Multipart<S>, field: &str) -> impl Stream<Item=Bytes, Error=Error>
+    where S: Stream<Item=Bytes, Error=error::PayloadError> + 'static {
+    select_fields(multipart, Rc::new(field.to_string()), false)
+        .take(1)
         .flatten()
         .from_err()
 }
 
-pub fn invoke_request_data(req: &HttpRequest) -> impl Stream<Item=Bytes, Error=Error> {
-    read_multipart(req.multipart())
-}
\ No newline at end of file
+pub fn invoke_request_data<S>(req: &HttpRequest<S>, field: &str) -> impl Stream<Item=Bytes, Error=Error> {
+    read_multipart(req.multipart(), field)
+}
+
+pub fn invoke_raw_data<S>(req: &HttpRequest<S>) -> impl Stream<Item=Bytes, Error=Error> {
+    req.payload().from_err()
+}
+
+#[derive(Default)]
+pub struct UploadForm {
+    pub fields: HashMap<String, String>,
+    pub files: Vec<PathBuf>,
+}
+
+enum FormPart {
+    Text(String, String),
+    File(PathBuf),
+}
+
+/// Текстовые поля формы короткие: имя, трекеры, комментарий
+const MAX_TEXT_FIELD: usize = 64 * 1024;
+
+/// Файлы из multipart-формы складываются в dir, текстовые поля возвращаются как есть.
+/// limit - на все файлы вместе; два файла с одним именем - ошибка, а не перезапись
+pub fn save_multipart<S>(req: &HttpRequest<S>, dir: PathBuf, limit: usize) -> impl Future<Item=UploadForm, Error=Error> {
+    let multipart = req.multipart();
+    let written = Rc::new(Cell::new(0));
+    let mut names = HashSet::new();
+    futures::future::result(std::fs::create_dir_all(&dir))
+        .from_err()
+        .and_then(move |_| multipart
+            .from_err::<Error>()
+            .filter_map(|item| match item {
+                MultipartItem::Field(f) => Some(f),
+                MultipartItem::Nested(_) => None,
+            })
+            .and_then(move |field| -> Box<Future<Item=FormPart, Error=Error>> {
+                let disposition = field.content_disposition();
+                let name = disposition.as_ref()
+                    .and_then(|d| d.get_name())
+                    .unwrap_or_default()
+                    .to_string();
+                let file_name = disposition.as_ref()
+                    .and_then(|d| d.get_filename())
+                    .and_then(|f| Path::new(f).file_name())
+                    .map(|f| f.to_os_string());
+                match file_name {
+                    Some(file_name) => {
+                        if !names.insert(file_name.clone()) {
+                            let message = format!("file {} is uploaded twice", file_name.to_string_lossy());
+                            return Box::new(futures::future::err(Error::from(ApiError::bad_request(message))));
+                        }
+                        let path = dir.join(file_name);
+                        let writer = storage::make_writer(path.clone());
+                        let written = written.clone();
+                        Box::new(field.from_err::<Error>()
+                            .and_then(move |chunk| {
+                                written.set(written.get() + chunk.len());
+                                if written.get() > limit {
+                                    return Err(Error::from(ApiError::TooLarge(limit)));
+                                }
+                                Ok(chunk)
+                            })
+                            .forward(writer.sink_from_err())
+                            .map(move |_| FormPart::File(path)))
+                    }
+                    None => Box::new(field.from_err::<Error>()
+                        .map_err(ApiError::from)
+                        .forward(storage::make_buffer(MAX_TEXT_FIELD, MAX_TEXT_FIELD))
+                        .map(move |(_, buffer)| FormPart::Text(name, String::from_utf8_lossy(buffer.as_ref()).into_owned()))
+                        .from_err::<Error>()),
+                }
+            })
+            .fold(UploadForm::default(), |mut form, part| {
+                match part {
+                    FormPart::Text(name, value) => { form.fields.insert(name, value); }
+                    FormPart::File(path) => form.files.push(path),
+                }
+                Ok::<_, Error>(form)
+            })
+        )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestServer;
+    use config::Config;
+    use metrics::Metrics;
+    use std::sync::Arc;
+
+    fn torrent(name: &str) -> Vec<u8> {
+        format!("d4:infod6:lengthi5e4:name{}:{}12:piece lengthi16384e6:pieces20:{}ee", name.len(), name, "a".repeat(20))
+            .into_bytes()
+    }
+
+    /// multipart-тело; части - заголовки и содержимое
+    fn multipart(boundary: &str, parts: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (headers, content) in parts {
+            body.extend(format!("--{}\r\n{}\r\n\r\n", boundary, headers).into_bytes());
+            body.extend_from_slice(content);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend(format!("--{}--\r\n", boundary).into_bytes());
+        body
+    }
+
+    struct Upload {
+        server: TestServer,
+        metainfo_dir: PathBuf,
+    }
+
+    impl Upload {
+        fn start(configure: fn(&mut Config)) -> Self {
+            let mut config = Config::default();
+            config.metainfo_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            config.torrent.data_dir = config.metainfo_dir.join("data");
+            std::fs::create_dir_all(&config.torrent.data_dir).unwrap();
+            configure(&mut config);
+            let metainfo_dir = config.metainfo_dir.clone();
+            let config = Arc::new(config);
+            let metrics = Arc::new(Metrics::default());
+            let torrents = ::torrent::new_service(config.torrent.clone(), metrics.clone());
+            let server = TestServer::with_factory(move || ::app(torrents.clone(), config.clone(), metrics.clone()));
+            Upload { server, metainfo_dir }
+        }
+
+        /// Статус и json-ответ POST /torrent
+        fn post(&mut self, content_type: &str, body: Vec<u8>) -> (StatusCode, serde_json::Value) {
+            self.post_to("/torrent", content_type, body)
+        }
+
+        fn post_to(&mut self, path: &str, content_type: &str, body: Vec<u8>) -> (StatusCode, serde_json::Value) {
+            let request = self.server.post().uri(self.server.url(path))
+                .header(header::CONTENT_TYPE, content_type)
+                .body(body)
+                .unwrap();
+            let response = self.server.execute(request.send()).unwrap();
+            let status = response.status();
+            let body = self.server.execute(response.body()).unwrap();
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+
+        /// Сколько .torrent попало в каталог (подкаталог data не в счет)
+        fn stored(&self) -> usize {
+            std::fs::read_dir(&self.metainfo_dir).unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().is_file())
+                .count()
+        }
+    }
+
+    impl Drop for Upload {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.metainfo_dir).ok();
+        }
+    }
+
+    const FORM: &str = "multipart/form-data; boundary=outer";
+
+    #[test]
+    fn test_upload_field() {
+        let mut upload = Upload::start(|_| {});
+        let body = multipart("outer", &[
+            ("Content-Disposition: form-data; name=\"comment\"", &b"not a torrent"[..]),
+            ("Content-Disposition: form-data; name=\"other\"; filename=\"b.torrent\"", &torrent("b.txt")[..]),
+            ("Content-Disposition: form-data; name=\"file\"; filename=\"a.torrent\"", &torrent("a.txt")[..]),
+        ]);
+        let (status, json) = upload.post(FORM, body);
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("a.txt", json["name"]);
+
+        let mut upload = Upload::start(|config| config.upload_field = "torrent".to_string());
+        let body = multipart("outer", &[
+            ("Content-Disposition: form-data; name=\"file\"; filename=\"a.torrent\"", &torrent("a.txt")[..]),
+            ("Content-Disposition: form-data; name=\"torrent\"; filename=\"b.torrent\"", &torrent("b.txt")[..]),
+        ]);
+        let (status, json) = upload.post(FORM, body);
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("b.txt", json["name"]);
+
+        let body = multipart("outer", &[("Content-Disposition: form-data; name=\"file\"", &torrent("a.txt")[..])]);
+        let (status, json) = upload.post(FORM, body);
+        assert_eq!((StatusCode::BAD_REQUEST, 1), (status, upload.stored()));
+        assert_eq!("bad_request", json["code"]);
+    }
+
+    #[test]
+    fn test_nested_multipart() {
+        let mut upload = Upload::start(|_| {});
+        //у вложенной части нет имени поля
+        let headers = "Content-Disposition: attachment; filename=\"a.torrent\"\r\nContent-Type: application/x-bittorrent";
+        let inner = multipart("inner", &[(headers, &torrent("a.txt")[..])]);
+        let body = multipart("outer", &[
+            ("Content-Disposition: form-data; name=\"file\"\r\nContent-Type: multipart/mixed; boundary=inner", &inner[..]),
+        ]);
+        let (status, json) = upload.post(FORM, body);
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("a.txt", json["name"]);
+        assert_eq!(1, upload.stored());
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut upload = Upload::start(|config| config.max_upload_size = 64);
+        let (status, json) = upload.post("application/x-bittorrent", torrent("a.txt"));
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+        assert_eq!("upload_too_large", json["code"]);
+        let body = multipart("outer", &[("Content-Disposition: form-data; name=\"file\"; filename=\"a\"", &torrent("a.txt")[..])]);
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, upload.post(FORM, body).0);
+        assert_eq!(0, upload.stored());
+    }
+
+    #[test]
+    fn test_limit_counts_field_only() {
+        assert_eq!(87, torrent("a.txt").len());
+        let mut upload = Upload::start(|config| config.max_upload_size = 87);
+        let body = multipart("outer", &[("Content-Disposition: form-data; name=\"file\"; filename=\"a\"", &torrent("a.txt")[..])]);
+        assert!(body.len() > 87);
+        let (status, json) = upload.post(FORM, body);
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("a.txt", json["name"]);
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, upload.post("application/x-bittorrent", torrent("ab.txt")).0);
+    }
+
+    #[test]
+    fn test_rejected_payloads() {
+        let mut upload = Upload::start(|_| {});
+        let (status, json) = upload.post("text/plain", torrent("a.txt"));
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, status);
+        assert_eq!("unsupported_media_type", json["code"]);
+        let (status, json) = upload.post("application/x-bittorrent", b"<html>not found</html>".to_vec());
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, status);
+        assert_eq!("invalid_metainfo", json["code"]);
+        let body = multipart("outer", &[("Content-Disposition: form-data; name=\"file\"; filename=\"a\"", &b"d3:fooe"[..])]);
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, upload.post(FORM, body).0);
+        assert_eq!(0, upload.stored());
+    }
+
+    #[test]
+    fn test_create_limits() {
+        let mut upload = Upload::start(|config| config.max_create_size = 8);
+        let file = |name: &str| format!("Content-Disposition: form-data; name=\"file\"; filename=\"{}\"", name);
+        let name = "Content-Disposition: form-data; name=\"name\"";
+
+        let body = multipart("outer", &[(name, &b"dir"[..]), (&file("a.txt")[..], &b"123"[..]), (&file("a.txt")[..], &b"45"[..])]);
+        let (status, json) = upload.post_to("/torrent/create", FORM, body);
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!("bad_request", json["code"]);
+
+        let body = multipart("outer", &[(name, &b"dir"[..]), (&file("a.txt")[..], &b"12345"[..]), (&file("b.txt")[..], &b"12345"[..])]);
+        let (status, json) = upload.post_to("/torrent/create", FORM, body);
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+        assert_eq!("upload_too_large", json["code"]);
+
+        let comment = vec![b'x'; MAX_TEXT_FIELD + 1];
+        let body = multipart("outer", &[("Content-Disposition: form-data; name=\"comment\"", &comment[..]), (&file("a.txt")[..], &b"1"[..])]);
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, upload.post_to("/torrent/create", FORM, body).0);
+
+        //временные каталоги загрузок убраны, в каталог данных ничего не попало
+        assert_eq!(0, std::fs::read_dir(upload.metainfo_dir.join("data")).unwrap().count());
+        assert_eq!(0, upload.stored());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let parse = |value| parse_range(value, 100).ok();
+        let range = |start, end| Some(Some(ByteRange { start, end }));
+        assert_eq!(range(0, 99), parse("bytes=0-"));
+        assert_eq!(range(10, 19), parse("bytes=10-19"));
+        assert_eq!(range(90, 99), parse("bytes=90-500"));
+        assert_eq!(range(80, 99), parse("bytes=-20"));
+        assert_eq!(range(0, 99), parse("bytes=-500"));
+        assert_eq!(Some(None), parse("bytes=0-1,5-6"));
+        assert_eq!(Some(None), parse("items=0-1"));
+        assert_eq!(Some(None), parse("bytes=5-1"));
+        match parse_range("bytes=100-", 100) {
+            Err(ApiError::RangeNotSatisfiable(100)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -1,28 +1,237 @@
+use bencode::{self, Value as Bencode};
 use bip_metainfo::MetainfoFile;
+use bytes::Bytes;
+use serde_json::{Map, Value};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct FileInfo {
+    pub index: usize, //номер файла в маршрутах /torrent/{hash}/file/{index}
+    pub path: String,
+    pub length: u64,
+    pub offset: u64, //смещение внутри торрента
+}
+
+/// Узел дерева файлов: каталог (children) или файл (index)
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TreeNode {
+    pub name: String,
+    pub length: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<TreeNode>,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct TorrentFile {
-    hash: String,
-    piece_length: u64,
-    size: u64,
-    announce: Option<String>,
-    comment: Option<String>,
-    files: Vec<String>
-}
-
-impl From<&MetainfoFile> for TorrentFile {
-    fn from(meta: &MetainfoFile) -> Self {
-        let piece_length = meta.info().piece_length();
-        let size = meta.info().files().map(|f|f.length()).sum();
+    pub hash: String,
+    pub name: String,
+    pub piece_length: u64,
+    pub pieces: usize,
+    pub size: u64,
+    pub private: bool,
+    pub creation_date: Option<i64>, //unix time
+    pub created_by: Option<String>,
+    pub announce: Option<String>,
+    pub announce_list: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub web_seeds: Vec<String>,
+    pub files: Vec<FileInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree: Option<Vec<TreeNode>>,
+}
+
+impl TorrentFile {
+    /// bytes - сам .torrent: bip_metainfo не читает url-list, его достаем сами
+    pub fn new(meta: &MetainfoFile, bytes: &[u8]) -> Self {
+        let info = meta.info();
+        let name = match info.directory() {
+            Some(dir) => dir.to_string_lossy().into_owned(),
+            None => info.files().next()
+                .map(|f| f.path().to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        let mut offset = 0;
+        let files: Vec<FileInfo> = info.files().enumerate().map(|(index, f)| {
+            let file = FileInfo {
+                index,
+                path: f.path().to_string_lossy().into_owned(),
+                length: f.length(),
+                offset,
+            };
+            offset += f.length();
+            file
+        }).collect();
         TorrentFile {
             hash: hex::encode(meta.info_hash()),
-            piece_length,
-            size,
+            name,
+            piece_length: info.piece_length(),
+            pieces: info.pieces().count(),
+            size: offset,
+            private: info.is_private().unwrap_or(false),
+            creation_date: meta.creation_date(),
+            created_by: meta.created_by().map(ToString::to_string),
             announce: meta.main_tracker().map(ToString::to_string),
+            announce_list: meta.trackers().cloned().unwrap_or_default(),
             comment: meta.comment().map(ToString::to_string),
-            files: meta.info().files()
-                .map(|f|f.path().to_str().unwrap_or("?").to_string())
-                .collect()
+            web_seeds: web_seeds(bytes),
+            files,
+            tree: None,
+        }
+    }
+
+    pub fn with_tree(mut self) -> Self {
+        self.tree = Some(build_tree(&self.files));
+        self
+    }
+}
+
+fn utf8(value: &Bencode) -> Option<String> {
+    value.as_str().map(ToString::to_string)
+}
+
+/// BEP 19: url-list - строка или список строк
+fn web_seeds(bytes: &[u8]) -> Vec<String> {
+    let dict = match bencode::decode(&Bytes::from(bytes)) {
+        Ok(Bencode::Dict(dict)) => dict,
+        _ => return Vec::new(),
+    };
+    match dict.get(b"url-list") {
+        Some(Bencode::List(list)) => list.iter().filter_map(utf8).collect(),
+        Some(value) => utf8(value).into_iter().collect(),
+        None => Vec::new(),
+    }.into_iter().filter(|url| !url.is_empty()).collect()
+}
+
+/// Весь .torrent как json, включая неизвестные ключи. Строки не в UTF-8 - {"hex": ...},
+/// pieces - список SHA-1 в hex. None, если это вообще не bencode.
+pub fn raw_metainfo(bytes: &[u8]) -> Option<Value> {
+    bencode::decode(&Bytes::from(bytes)).ok().map(|value| raw_value(&value, false))
+}
+
+fn raw_bytes(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Value::String(text.to_string()),
+        Err(_) => json!({ "hex": hex::encode(bytes) }),
+    }
+}
+
+fn raw_value(value: &Bencode, pieces: bool) -> Value {
+    match value {
+        Bencode::Int(n) => Value::from(*n),
+        Bencode::Bytes(bytes) if pieces && bytes.len() % 20 == 0 => {
+            Value::Array(bytes.chunks(20).map(|hash| Value::String(hex::encode(hash))).collect())
+        }
+        Bencode::Bytes(bytes) => raw_bytes(bytes),
+        Bencode::List(list) => Value::Array(list.iter().map(|v| raw_value(v, false)).collect()),
+        Bencode::Dict(dict) => {
+            let mut map = Map::new();
+            for (key, value) in dict.iter() {
+                let name = match std::str::from_utf8(key) {
+                    Ok(name) => name.to_string(),
+                    Err(_) => format!("hex:{}", hex::encode(key)),
+                };
+                map.insert(name, raw_value(value, &key[..] == b"pieces"));
+            }
+            Value::Object(map)
+        }
+    }
+}
+
+fn build_tree(files: &[FileInfo]) -> Vec<TreeNode> {
+    let mut root: Vec<TreeNode> = Vec::new();
+    for file in files {
+        let parts: Vec<&str> = file.path.split('/').filter(|p| !p.is_empty()).collect();
+        let (name, dirs) = match parts.split_last() {
+            Some(split) => split,
+            None => continue,
+        };
+        let mut level = &mut root;
+        for dir in dirs {
+            let position = match level.iter().position(|node| node.index.is_none() && node.name == *dir) {
+                Some(position) => position,
+                None => {
+                    level.push(TreeNode { name: dir.to_string(), length: 0, index: None, children: Vec::new() });
+                    level.len() - 1
+                }
+            };
+            let node = &mut level[position];
+            node.length += file.length;
+            level = &mut node.children;
         }
+        level.push(TreeNode { name: name.to_string(), length: file.length, index: Some(file.index), children: Vec::new() });
+    }
+    root
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file(index: usize, path: &str, length: u64) -> FileInfo {
+        FileInfo { index, path: path.to_string(), length, offset: 0 }
+    }
+
+    fn leaf(name: &str, length: u64, index: usize) -> TreeNode {
+        TreeNode { name: name.to_string(), length, index: Some(index), children: Vec::new() }
+    }
+
+    #[test]
+    fn test_tree() {
+        let files = vec![
+            file(0, "Season 1/e01.mkv", 10),
+            file(1, "Season 1/e02.mkv", 20),
+            file(2, "cover.jpg", 1),
+            file(3, "Season 1/subs/e01.srt", 2),
+        ];
+        assert_eq!(vec![
+            TreeNode {
+                name: "Season 1".to_string(),
+                length: 32,
+                index: None,
+                children: vec![
+                    leaf("e01.mkv", 10, 0),
+                    leaf("e02.mkv", 20, 1),
+                    TreeNode {
+                        name: "subs".to_string(),
+                        length: 2,
+                        index: None,
+                        children: vec![leaf("e01.srt", 2, 3)],
+                    },
+                ],
+            },
+            leaf("cover.jpg", 1, 2),
+        ], build_tree(&files));
+    }
+
+    #[test]
+    fn test_raw_metainfo() {
+        let mut torrent = b"d4:infod5:filesld6:lengthi1e4:pathl3:\xff\xfe\x41eee4:name1:x12:piece lengthi16384e6:pieces40:".to_vec();
+        torrent.extend_from_slice(&[0xab; 20]);
+        torrent.extend_from_slice(&[0xcd; 20]);
+        torrent.extend_from_slice(b"e9:x-vendori7ee");
+        assert_eq!(Some(json!({
+            "info": {
+                "files": [{ "length": 1, "path": [{ "hex": "fffe41" }] }],
+                "name": "x",
+                "piece length": 16384,
+                "pieces": ["ab".repeat(20), "cd".repeat(20)],
+            },
+            "x-vendor": 7,
+        })), raw_metainfo(&torrent));
+        assert_eq!(None, raw_metainfo(b"not bencode"));
+    }
+
+    #[test]
+    fn test_web_seeds() {
+        assert_eq!(
+            vec!["http://a/".to_string()],
+            web_seeds(b"d8:url-list9:http://a/4:infod6:lengthi1eee")
+        );
+        assert_eq!(
+            vec!["http://a/".to_string(), "http://b/".to_string()],
+            web_seeds(b"d8:url-listl9:http://a/9:http://b/0:ee")
+        );
+        assert!(web_seeds(b"d4:infod6:lengthi1eee").is_empty());
     }
 }
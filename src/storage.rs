@@ -9,11 +9,12 @@ use futures::Future;
 use futures::stream::Stream;
 use std::marker::PhantomData;
 use failure::{Fail,Error};
+use std::path::{Path, PathBuf};
 
 
-struct NullSink<I,E>(PhantomData<I>,PhantomData<E>);
+pub struct NullSink<I,E>(PhantomData<I>,PhantomData<E>);
 impl<I,E> NullSink<I,E> {
-    fn new() -> Self {
+    pub fn new() -> Self {
         NullSink(PhantomData,PhantomData)
     }
 }
@@ -39,14 +40,20 @@ pub struct CachedSink<T: Sink> {
     cache: BytesMut,
     sink: T,
     synchronized: bool,
+    limit: usize,
 }
 
 impl<T: Sink> CachedSink<T> {
     pub fn new(sink: T, size: usize) -> CachedSink<T> {
+        CachedSink::growing(sink, size, size)
+    }
+    /// Когда размер заранее неизвестен (chunked): буфер растет по мере надобности, но не больше limit
+    pub fn growing(sink: T, capacity: usize, limit: usize) -> CachedSink<T> {
         CachedSink {
-            cache: BytesMut::with_capacity(size),
+            cache: BytesMut::with_capacity(std::cmp::min(capacity, limit)),
             sink,
             synchronized: true,
+            limit,
         }
     }
     fn to_bytes(self) -> Bytes {
@@ -76,9 +83,12 @@ impl<T, I, E> Sink for CachedSink<T>
     fn start_send(&mut self, item: Self::SinkItem) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
         if self.synchronized {
             let item = item.as_ref();
-            if item.len() > self.cache.remaining_mut() {
+            if self.cache.len() + item.len() > self.limit {
                 return Err(CachedSinkError.into());
             }
+            if item.len() > self.cache.remaining_mut() {
+                self.cache.reserve(item.len());
+            }
             self.cache.put(item);
         }
         let ret = self.sink.start_send(item);
@@ -99,20 +109,32 @@ impl<T, I, E> Sink for CachedSink<T>
     }
 }
 
-pub fn make_writer(name: String) -> FsWriteSink {
-    FsPool::default().write(name, Default::default())
+/// Буфер в памяти: ничего не пишет на диск, пока содержимое не проверено
+pub fn make_buffer<I: AsRef<[u8]>>(capacity: usize, limit: usize) -> CachedSink<NullSink<I, Error>> {
+    CachedSink::growing(NullSink::new(), capacity, limit)
+}
+
+pub fn make_writer(path: PathBuf) -> FsWriteSink {
+    FsPool::default().write(path, Default::default())
+}
+
+pub fn make_reader(path: PathBuf) -> FsReadStream {
+    FsPool::default().read(path, Default::default())
 }
 
-pub fn make_reader(hash: String) -> FsReadStream {
-    FsPool::default().read(hash, Default::default())
+pub fn store(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, bytes)
 }
 
-pub fn read(hash: String) -> impl Future<Item=Bytes, Error=Error> {
-    let size = std::fs::metadata(&hash).unwrap().len() as usize;
-    make_reader(hash)
-        .from_err::<Error>()
-        .forward(CachedSink::new(NullSink::<_,Error>::new(),size))
-        .map(|(_,sink)|sink.to_bytes())
+pub fn read(path: PathBuf) -> impl Future<Item=Bytes, Error=Error> {
+    futures::future::result(std::fs::metadata(&path))
+        .from_err()
+        .and_then(move |meta| {
+            make_reader(path)
+                .from_err::<Error>()
+                .forward(CachedSink::new(NullSink::<_,Error>::new(), meta.len() as usize))
+                .map(|(_,sink)|sink.to_bytes())
+        })
 }
 
 #[cfg(test)]
@@ -130,6 +152,19 @@ mod test {
         err.into()
     }
     #[test]
+    fn test_growing_cache() {
+        use futures::Sink;
+        use storage::make_buffer;
+        let mut sink = make_buffer::<&[u8]>(2, 6);
+        sink.start_send(b"abc".as_ref()).unwrap();
+        sink.start_send(b"def".as_ref()).unwrap();
+        assert_eq!(b"abcdef".as_ref(), sink.as_ref());
+        assert!(sink.start_send(b"g".as_ref()).is_err());
+
+        let mut sink = make_buffer::<&[u8]>(4, 4);
+        assert!(sink.start_send(b"abcde".as_ref()).is_err());
+    }
+    #[test]
     fn from_io_to_failure() {
         let err1 = io::Error::from_raw_os_error(0);
         //let err2 = io::Error::from_raw_os_error(0);
@@ -0,0 +1,332 @@
+//! Рой на localhost: подставной http-трекер и сиды, раздающие сгенерированный торрент.
+//! Сервис качает через настоящий /torrent/download, результат сверяется байт в байт.
+
+use super::*;
+use actix_web::test::TestServer;
+use actix_web::HttpMessage;
+use bencode::{Dict, Value};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::timer::Timeout;
+
+const PIECE_LENGTH: usize = 32 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(60);
+const SLOW_BLOCK: Duration = Duration::from_millis(5);
+
+/// Детерминированный мусор: xorshift
+fn content(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 24) as u8
+    }).collect()
+}
+
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+struct Torrent {
+    metainfo: Vec<u8>,
+    info_hash: [u8; 20],
+    data: Vec<u8>, //все файлы подряд
+}
+
+impl Torrent {
+    /// Один файл - files из одного элемента без каталогов
+    fn new(name: &str, files: &[(&str, usize)], announce: &str) -> Self {
+        let mut data = Vec::new();
+        let mut list = Vec::new();
+        for (seed, &(path, length)) in files.iter().enumerate() {
+            data.extend(content(seed as u64 + 1, length));
+            let mut file = Dict::new();
+            file.insert("length", length as i64);
+            file.insert("path", path.split('/').map(Value::from).collect::<Vec<_>>());
+            list.push(Value::from(file));
+        }
+        let pieces: Vec<u8> = data.chunks(PIECE_LENGTH)
+            .flat_map(|piece| sha1::Sha1::from(piece).digest().bytes().to_vec())
+            .collect();
+        let mut info = Dict::new();
+        if files.len() == 1 {
+            info.insert("length", data.len() as i64);
+            info.insert("name", files[0].0);
+        } else {
+            info.insert("files", list);
+            info.insert("name", name);
+        }
+        info.insert("piece length", PIECE_LENGTH as i64);
+        info.insert("pieces", pieces.as_slice());
+        let info = Value::from(info);
+        let info_hash = sha1::Sha1::from(&info.encode()[..]).digest().bytes();
+        let mut metainfo = Dict::new();
+        metainfo.insert("announce", announce);
+        metainfo.insert("info", info);
+        Torrent { metainfo: Value::from(metainfo).encode(), info_hash, data }
+    }
+
+    fn hash(&self) -> String {
+        hex::encode(self.info_hash)
+    }
+}
+
+/// Трекер на каждый анонс отдает всех сидов; запросы запоминает
+struct Tracker {
+    url: String,
+    announces: Arc<Mutex<Vec<String>>>,
+}
+
+impl Tracker {
+    fn start(seeders: Vec<SocketAddr>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let announces = Arc::new(Mutex::new(Vec::new()));
+        let log = announces.clone();
+        let mut peers = Vec::new();
+        for addr in &seeders {
+            if let SocketAddr::V4(addr) = addr {
+                peers.extend_from_slice(&addr.ip().octets());
+                peers.extend_from_slice(&[(addr.port() >> 8) as u8, addr.port() as u8]);
+            }
+        }
+        let mut body = format!("d8:completei{}e10:incompletei0e8:intervali1800e5:peers{}:", seeders.len(), peers.len())
+            .into_bytes();
+        body.extend(peers);
+        body.push(b'e');
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let line = String::from_utf8_lossy(&request).lines().next().unwrap_or_default().to_string();
+                log.lock().unwrap().push(line);
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                ).into_bytes();
+                response.extend_from_slice(&body);
+                stream.write_all(&response).ok();
+            }
+        });
+        Tracker { url, announces }
+    }
+
+    fn announces(&self) -> Vec<String> {
+        self.announces.lock().unwrap().clone()
+    }
+}
+
+fn send(stream: &mut TcpStream, id: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut message = ((payload.len() + 1) as u32).to_be_bytes().to_vec();
+    message.push(id);
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> usize {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_be_bytes(buf) as usize
+}
+
+/// Сид со всеми кусками
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Seeder {
+    Fast,
+    Slow, //блок в SLOW_BLOCK: пока он отдает, остальные сиды успевают подключиться
+    DropAfter(usize), //оборвать соединение, отдав столько блоков
+}
+
+/// drops - сколько соединений оборвали сиды DropAfter
+fn seeder(info_hash: [u8; 20], data: Arc<Vec<u8>>, kind: Seeder, drops: Arc<AtomicUsize>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let (data, drops) = (data.clone(), drops.clone());
+                std::thread::spawn(move || serve_peer(stream, info_hash, &data, kind, &drops));
+            }
+        }
+    });
+    addr
+}
+
+fn serve_peer(mut stream: TcpStream, info_hash: [u8; 20], data: &[u8], kind: Seeder, drops: &AtomicUsize)
+    -> std::io::Result<()> {
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake)?;
+    if handshake[28..48] != info_hash {
+        return Ok(());
+    }
+    let mut reply = vec![19u8];
+    reply.extend_from_slice(b"BitTorrent protocol");
+    reply.extend_from_slice(&[0u8; 8]);
+    reply.extend_from_slice(&info_hash);
+    reply.extend_from_slice(b"-TR2940-swarmtesting");
+    stream.write_all(&reply)?;
+
+    let pieces = (data.len() + PIECE_LENGTH - 1) / PIECE_LENGTH;
+    let mut bitfield = vec![0xffu8; (pieces + 7) / 8];
+    if pieces % 8 != 0 {
+        *bitfield.last_mut().unwrap() = 0xff << (8 - pieces % 8);
+    }
+    send(&mut stream, 5, &bitfield)?;
+    send(&mut stream, 1, &[])?;
+
+    let mut served = 0;
+    loop {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length)?;
+        let mut message = vec![0u8; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut message)?;
+        if message.first() != Some(&6) {
+            continue; //keep-alive, interested, have и прочее сиду не важны
+        }
+        match kind {
+            Seeder::DropAfter(limit) if served == limit => {
+                drops.fetch_add(1, Ordering::SeqCst);
+                return Ok(());
+            }
+            Seeder::Slow => std::thread::sleep(SLOW_BLOCK),
+            _ => {}
+        }
+        let (index, begin, length) = (u32_at(&message, 1), u32_at(&message, 5), u32_at(&message, 9));
+        let start = index * PIECE_LENGTH + begin;
+        let mut piece = message[1..9].to_vec();
+        piece.extend_from_slice(&data[start..start + length]);
+        send(&mut stream, 7, &piece)?;
+        served += 1;
+    }
+}
+
+struct Swarm {
+    server: TestServer,
+    tracker: Tracker,
+    torrent: Torrent,
+    drops: Arc<AtomicUsize>,
+    dirs: Vec<PathBuf>,
+}
+
+impl Swarm {
+    fn start(name: &str, files: &[(&str, usize)], seeders: &[Seeder]) -> Self {
+        //адрес трекера попадает в .torrent, а трекеру нужны адреса сидов. announce не входит в info,
+        //так что сиды получают тот же торрент без трекера.
+        let probe = Torrent::new(name, files, "");
+        let data = Arc::new(probe.data);
+        let drops = Arc::new(AtomicUsize::new(0));
+        let addrs = seeders.iter().map(|&kind| seeder(probe.info_hash, data.clone(), kind, drops.clone())).collect();
+        let tracker = Tracker::start(addrs);
+        let torrent = Torrent::new(name, files, &tracker.url);
+
+        let mut config = Config::default();
+        config.metainfo_dir = temp_dir();
+        config.torrent.data_dir = temp_dir();
+        let dirs = vec![config.metainfo_dir.clone(), config.torrent.data_dir.clone()];
+        let config = Arc::new(config);
+        let metrics = Arc::new(Metrics::default());
+        let torrents = torrent::new_service(config.torrent.clone(), metrics.clone());
+        let server = TestServer::with_factory(move || app(torrents.clone(), config.clone(), metrics.clone()));
+        Swarm { server, tracker, torrent, drops, dirs }
+    }
+
+    fn drops(&self) -> usize {
+        self.drops.load(Ordering::SeqCst)
+    }
+
+    fn get(&mut self, path: &str) -> Vec<u8> {
+        let request = self.server.get().uri(self.server.url(path)).finish().unwrap();
+        let limit = self.torrent.data.len() + 1;
+        let response = self.server.execute(Timeout::new(request.send().timeout(TIMEOUT).from_err::<failure::Error>()
+            .and_then(move |response| {
+                assert!(response.status().is_success(), "{}", response.status());
+                response.body().limit(limit).from_err()
+            }), TIMEOUT));
+        match response {
+            Ok(body) => body.to_vec(),
+            Err(e) => panic!("GET {}: {:?}", path, e.into_inner().map(|e| e.to_string())),
+        }
+    }
+
+    fn upload(&mut self) {
+        let request = self.server.post().uri(self.server.url("/torrent"))
+            .header(header::CONTENT_TYPE, "application/x-bittorrent")
+            .body(self.torrent.metainfo.clone())
+            .unwrap();
+        let response = self.server.execute(request.send()).unwrap();
+        assert!(response.status().is_success(), "upload: {}", response.status());
+    }
+
+    fn download(&mut self) -> Vec<u8> {
+        self.upload();
+        let path = format!("/torrent/download?hash={}", self.torrent.hash());
+        self.get(&path)
+    }
+}
+
+impl Drop for Swarm {
+    fn drop(&mut self) {
+        for dir in &self.dirs {
+            std::fs::remove_dir_all(dir).ok();
+        }
+    }
+}
+
+/// Сравнение без вывода мегабайт в панике
+fn assert_same(expected: &[u8], actual: &[u8]) {
+    assert_eq!(expected.len(), actual.len());
+    let diff = expected.iter().zip(actual.iter()).position(|(a, b)| a != b);
+    assert_eq!(None, diff, "content differs");
+}
+
+#[test]
+fn test_single_file() {
+    let mut swarm = Swarm::start("movie.mkv", &[("movie.mkv", PIECE_LENGTH * 5 + 1234)], &[Seeder::Fast, Seeder::Fast, Seeder::Fast]);
+    let data = swarm.download();
+    assert_same(&swarm.torrent.data, &data);
+    let announces = swarm.tracker.announces();
+    assert!(announces.iter().any(|line| line.contains("event=started")), "{:?}", announces);
+    assert!(announces.iter().any(|line| line.contains(&format!("left={}", swarm.torrent.data.len()))));
+}
+
+#[test]
+fn test_multi_file() {
+    let files = [
+        ("Season 1/e01.mkv", PIECE_LENGTH * 2 + 100),
+        ("Season 1/e02.mkv", 7),
+        ("cover.jpg", PIECE_LENGTH - 50),
+        ("Season 1/subs/e01.srt", PIECE_LENGTH * 3),
+    ];
+    let mut swarm = Swarm::start("show", &files, &[Seeder::Fast, Seeder::Fast]);
+    let data = swarm.download();
+    assert_same(&swarm.torrent.data, &data);
+    let path = format!("/torrent/{}/file/2", swarm.torrent.hash());
+    let offset = files[0].1 + files[1].1;
+    let expected = swarm.torrent.data[offset..offset + files[2].1].to_vec();
+    assert_same(&expected, &swarm.get(&path));
+}
+
+#[test]
+fn test_peers_drop_mid_transfer() {
+    let seeders = [Seeder::DropAfter(3), Seeder::DropAfter(5), Seeder::Slow];
+    let mut swarm = Swarm::start("drops.bin", &[("drops.bin", PIECE_LENGTH * 32 + 10)], &seeders);
+    let data = swarm.download();
+    assert_same(&swarm.torrent.data, &data);
+    //иначе тест прошел бы и без обрывов
+    assert_eq!(2, swarm.drops());
+}
@@ -0,0 +1,294 @@
+//! Расписание анонсов одному трекеру: started, повторы по interval, completed и stopped
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use super::stats::{self, TrackerStats, TrackerStatus};
+use super::tracker::{AnnounceResponse, TrackerEvent};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60); //пока трекер не назвал свой
+const MIN_INTERVAL: Duration = Duration::from_secs(60); //чаще не анонсируемся, что бы ни просил трекер
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Completion {
+    Downloading,
+    Pending, //докачали, трекер еще не знает
+    Sent,
+}
+
+pub struct Announcer {
+    url: String,
+    interval: Duration,
+    min_interval: Duration,
+    tracker_id: Option<String>,
+    started: bool, //трекер принял started
+    completion: Completion,
+    last: Option<Instant>, //когда отправлен последний анонс
+    next: Instant,
+    sending: Option<Option<TrackerEvent>>, //анонс в пути и его событие
+    failures: u32,
+    message: Option<String>,
+    warning: Option<String>,
+    seeders: Option<usize>,
+    leechers: Option<usize>,
+}
+
+/// RETRY_INTERVAL, удваиваясь с каждой неудачей подряд до MAX_BACKOFF. jitter из [0, 1) разносит
+/// повторы торрентов одного трекера: ждем от половины до полного срока.
+fn backoff(failures: u32, jitter: f64) -> Duration {
+    let exponent = std::cmp::min(failures.saturating_sub(1), 6);
+    let delay = std::cmp::min(RETRY_INTERVAL * (1 << exponent), MAX_BACKOFF);
+    let millis = delay.as_secs() * 1000 / 2;
+    Duration::from_millis(millis + (millis as f64 * jitter) as u64)
+}
+
+fn jitter() -> f64 {
+    f64::from(uuid::Uuid::new_v4().as_bytes()[0]) / 256.0
+}
+
+impl Announcer {
+    pub fn new(url: String, now: Instant) -> Self {
+        Announcer {
+            url,
+            interval: DEFAULT_INTERVAL,
+            min_interval: MIN_INTERVAL,
+            tracker_id: None,
+            started: false,
+            completion: Completion::Downloading,
+            last: None,
+            next: now,
+            sending: None,
+            failures: 0,
+            message: None,
+            warning: None,
+            seeders: None,
+            leechers: None,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Трекер просит присылать его tracker id в следующих анонсах
+    pub fn tracker_id(&self) -> Option<&str> {
+        self.tracker_id.as_ref().map(String::as_str)
+    }
+
+    /// Трекер знает о нас, и при остановке ему нужен stopped
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Пора по расписанию; completed и нехватка пиров - раньше срока, но не чаще min interval.
+    /// После ошибок ждем только расписания: оно уже отложено с backoff.
+    pub fn is_due(&self, now: Instant, want_peers: bool) -> bool {
+        if self.sending.is_some() {
+            return false;
+        }
+        if now >= self.next {
+            return true;
+        }
+        let allowed = self.failures == 0 && self.last.map_or(true, |last| now >= last + self.min_interval);
+        allowed && (want_peers || self.completion == Completion::Pending)
+    }
+
+    /// Начать анонс; возвращает его событие
+    pub fn start(&mut self, now: Instant) -> Option<TrackerEvent> {
+        let event = if !self.started {
+            Some(TrackerEvent::Started)
+        } else if self.completion == Completion::Pending {
+            Some(TrackerEvent::Completed)
+        } else {
+            None
+        };
+        self.last = Some(now);
+        self.sending = Some(event);
+        event
+    }
+
+    /// Сколько ждали ответа на текущий анонс
+    pub fn elapsed(&self, now: Instant) -> Option<Duration> {
+        self.last.filter(|_| self.sending.is_some()).map(|last| now - last)
+    }
+
+    /// Ответ трекера; Ok - адреса пиров, Err - причина неудачи
+    pub fn on_response(&mut self, now: Instant, response: AnnounceResponse) -> Result<Vec<SocketAddr>, String> {
+        let (interval, min_interval, tracker_id, warning, seeders, leechers, peers) = match response {
+            AnnounceResponse::Success { interval, min_interval, tracker_id, warning_message, complete, incomplete, peers } =>
+                (interval, min_interval, tracker_id, warning_message, complete, incomplete, peers),
+            AnnounceResponse::Failure(e) => return Err(self.on_failure(now, e.to_string())),
+        };
+        match self.sending.take() {
+            Some(Some(TrackerEvent::Started)) => self.started = true,
+            Some(Some(TrackerEvent::Completed)) => self.completion = Completion::Sent,
+            _ => {}
+        }
+        self.interval = std::cmp::max(Duration::from_secs(interval as u64), MIN_INTERVAL);
+        self.min_interval = min_interval
+            .map(|seconds| Duration::from_secs(seconds as u64))
+            .map_or(MIN_INTERVAL, |min_interval| std::cmp::max(min_interval, MIN_INTERVAL));
+        if tracker_id.is_some() {
+            self.tracker_id = tracker_id;
+        }
+        self.next = now + self.interval;
+        self.failures = 0;
+        self.message = None;
+        self.warning = warning;
+        self.seeders = Some(seeders);
+        self.leechers = Some(leechers);
+        Ok(peers.iter().map(|peer| peer.addr()).collect())
+    }
+
+    /// Неудачный анонс повторим с тем же событием, с растущей паузой; возвращает ошибку
+    pub fn on_failure(&mut self, now: Instant, message: String) -> String {
+        self.sending = None;
+        self.failures += 1;
+        self.next = now + backoff(self.failures, jitter());
+        self.message = Some(message.clone());
+        message
+    }
+
+    /// completed еще не принят трекером; переживает рестарт через resume-файл
+    pub fn is_completion_pending(&self) -> bool {
+        self.completion == Completion::Pending
+    }
+
+    /// Торрент докачан в этой сессии; если он был полным при старте, completed не нужен
+    pub fn complete(&mut self) {
+        if self.completion == Completion::Downloading {
+            self.completion = Completion::Pending;
+        }
+    }
+
+    pub fn stats(&self, now: Instant) -> TrackerStats {
+        let status = match (self.sending.is_some(), self.last, self.failures) {
+            (true, _, _) => TrackerStatus::Updating,
+            (false, None, _) => TrackerStatus::NotContacted,
+            (false, Some(_), 0) => TrackerStatus::Working,
+            (false, Some(_), _) => TrackerStatus::Failed,
+        };
+        TrackerStats {
+            url: self.url.clone(),
+            status,
+            message: self.message.clone(),
+            warning: self.warning.clone(),
+            failures: self.failures,
+            last_announce: self.last.and_then(|last| stats::unix_time(last, now)),
+            next_announce: stats::unix_time(self.next, now),
+            seeders: self.seeders,
+            leechers: self.leechers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use torrent::tracker::AnnounceResponseError;
+
+    fn success(interval: usize, min_interval: Option<usize>, tracker_id: Option<&str>) -> AnnounceResponse {
+        AnnounceResponse::Success {
+            warning_message: None,
+            interval,
+            min_interval,
+            tracker_id: tracker_id.map(ToString::to_string),
+            complete: 3,
+            incomplete: 4,
+            peers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let now = Instant::now();
+        let mut announcer = Announcer::new("http://t/announce".to_string(), now);
+        assert_eq!(TrackerStatus::NotContacted, announcer.stats(now).status);
+        assert!(announcer.is_due(now, false));
+        assert_eq!(Some(TrackerEvent::Started), announcer.start(now));
+        assert_eq!(TrackerStatus::Updating, announcer.stats(now).status);
+        assert!(!announcer.is_due(now, true));
+        announcer.on_failure(now, "timeout".to_string());
+        assert!(!announcer.is_started());
+        assert!(!announcer.is_due(now + Duration::from_secs(29), true));
+
+        let now = now + RETRY_INTERVAL;
+        assert!(announcer.is_due(now, false));
+        assert_eq!(Some(TrackerEvent::Started), announcer.start(now));
+        assert!(announcer.on_response(now, success(1800, Some(300), Some("id"))).is_ok());
+        assert!(announcer.is_started());
+        assert_eq!(Some("id"), announcer.tracker_id());
+
+        //мало пиров - раньше срока, но не чаще min interval
+        assert!(!announcer.is_due(now + Duration::from_secs(299), true));
+        assert!(announcer.is_due(now + Duration::from_secs(300), true));
+        assert!(!announcer.is_due(now + Duration::from_secs(300), false));
+        assert!(announcer.is_due(now + Duration::from_secs(1800), false));
+
+        let now = now + Duration::from_secs(1800);
+        assert_eq!(None, announcer.start(now));
+        announcer.complete();
+        announcer.on_response(now, success(1800, None, None)).unwrap();
+        assert_eq!(Some("id"), announcer.tracker_id());
+
+        //completed ждет только min interval и уходит один раз
+        assert!(!announcer.is_due(now + Duration::from_secs(59), false));
+        let now = now + MIN_INTERVAL;
+        assert!(announcer.is_due(now, false) && announcer.is_completion_pending());
+        assert_eq!(Some(TrackerEvent::Completed), announcer.start(now));
+        announcer.on_response(now, success(1800, None, None)).unwrap();
+        assert!(!announcer.is_completion_pending());
+        announcer.complete();
+        assert!(!announcer.is_due(now + MIN_INTERVAL, false));
+        assert_eq!(None, announcer.start(now + Duration::from_secs(1800)));
+    }
+
+    #[test]
+    fn test_interval_floor() {
+        let now = Instant::now();
+        let mut announcer = Announcer::new("http://t/announce".to_string(), now);
+        announcer.start(now);
+        announcer.on_response(now, success(0, Some(0), None)).unwrap();
+        assert!(!announcer.is_due(now + Duration::from_secs(59), true));
+        assert!(announcer.is_due(now + MIN_INTERVAL, false));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(Duration::from_secs(30), backoff(1, 0.0));
+        assert_eq!(Duration::from_secs(60), backoff(2, 0.0));
+        assert_eq!(Duration::from_secs(90), backoff(2, 0.5));
+        assert_eq!(Duration::from_secs(30 * 60), backoff(7, 0.0));
+        assert_eq!(Duration::from_secs(30 * 60), backoff(100, 0.0));
+        assert!(backoff(100, 0.999) < MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_failure_status() {
+        let now = Instant::now();
+        let mut announcer = Announcer::new("http://t/announce".to_string(), now);
+        for _ in 0..3 {
+            announcer.start(now);
+            let error = AnnounceResponse::Failure(AnnounceResponseError::FailureMessage("banned".to_string()));
+            assert!(announcer.on_response(now, error).is_err());
+        }
+        let stats = announcer.stats(now);
+        assert_eq!((TrackerStatus::Failed, 3), (stats.status, stats.failures));
+        assert_eq!(Some("received error message from tracker: banned".to_string()), stats.message);
+        //между повторами не анонсируемся даже ради пиров
+        assert!(!announcer.is_due(now + Duration::from_secs(119), true));
+        assert!(announcer.is_due(now + Duration::from_secs(240), false));
+
+        announcer.start(now);
+        let mut response = success(1800, None, None);
+        if let AnnounceResponse::Success { ref mut warning_message, .. } = response {
+            *warning_message = Some("slow down".to_string());
+        }
+        announcer.on_response(now, response).unwrap();
+        let stats = announcer.stats(now);
+        assert_eq!((TrackerStatus::Working, 0, None), (stats.status, stats.failures, stats.message));
+        assert_eq!((Some("slow down".to_string()), Some(3), Some(4)), (stats.warning, stats.seeders, stats.leechers));
+        assert!(stats.next_announce.unwrap() >= stats.last_announce.unwrap() + 1800);
+    }
+}
@@ -0,0 +1,215 @@
+//! Битовое поле кусков в формате протокола: старший бит первого байта - кусок 0
+
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct BitfieldError(String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: u32, //число кусков; хвостовые биты последнего байта всегда нулевые
+}
+
+fn byte_count(len: u32) -> usize {
+    (len as usize + 7) / 8
+}
+
+fn mask(index: u32) -> u8 {
+    0x80 >> (index % 8)
+}
+
+/// Маска значимых битов последнего байта
+fn tail_mask(len: u32) -> u8 {
+    match len % 8 {
+        0 => 0xff,
+        bits => 0xff << (8 - bits),
+    }
+}
+
+impl Bitfield {
+    pub fn new(len: u32) -> Self {
+        Bitfield { bytes: vec![0; byte_count(len)], len }
+    }
+
+    pub fn full(len: u32) -> Self {
+        let mut bytes = vec![0xff; byte_count(len)];
+        if let Some(last) = bytes.last_mut() {
+            *last &= tail_mask(len);
+        }
+        Bitfield { bytes, len }
+    }
+
+    /// Поле от пира или из resume-файла: длина должна совпадать, лишние биты - быть нулями
+    pub fn from_bytes(bytes: &[u8], len: u32) -> Result<Self, BitfieldError> {
+        if bytes.len() != byte_count(len) {
+            return Err(BitfieldError(format!("bitfield of {} bytes for {} pieces", bytes.len(), len)));
+        }
+        if let Some(last) = bytes.last() {
+            if last & !tail_mask(len) != 0 {
+                return Err(BitfieldError(format!("spare bits are set in bitfield for {} pieces", len)));
+            }
+        }
+        Ok(Bitfield { bytes: bytes.to_vec(), len })
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// false и за пределами поля
+    pub fn get(&self, index: u32) -> bool {
+        index < self.len && self.bytes[(index / 8) as usize] & mask(index) != 0
+    }
+
+    fn check(&self, index: u32) -> Result<usize, BitfieldError> {
+        if index < self.len {
+            Ok((index / 8) as usize)
+        } else {
+            Err(BitfieldError(format!("piece {} is out of {}", index, self.len)))
+        }
+    }
+
+    pub fn set(&mut self, index: u32) -> Result<(), BitfieldError> {
+        let byte = self.check(index)?;
+        self.bytes[byte] |= mask(index);
+        Ok(())
+    }
+
+    pub fn clear(&mut self, index: u32) -> Result<(), BitfieldError> {
+        let byte = self.check(index)?;
+        self.bytes[byte] &= !mask(index);
+        Ok(())
+    }
+
+    pub fn count_ones(&self) -> u32 {
+        self.bytes.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    pub fn count_zeros(&self) -> u32 {
+        self.len - self.count_ones()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count_zeros() == 0
+    }
+
+    /// Установленные биты по возрастанию; нулевые байты пропускаются целиком
+    pub fn ones<'a>(&'a self) -> impl Iterator<Item=u32> + 'a {
+        bits(&self.bytes, 0)
+    }
+
+    pub fn zeros<'a>(&'a self) -> impl Iterator<Item=u32> + 'a {
+        let len = self.len;
+        bits(&self.bytes, 0xff).filter(move |&index| index < len)
+    }
+
+    fn zip(&self, other: &Bitfield, op: fn(u8, u8) -> u8) -> Result<Bitfield, BitfieldError> {
+        if self.len != other.len {
+            return Err(BitfieldError(format!("bitfields of {} and {} pieces", self.len, other.len)));
+        }
+        let bytes = self.bytes.iter().zip(other.bytes.iter()).map(|(&a, &b)| op(a, b)).collect();
+        Ok(Bitfield { bytes, len: self.len })
+    }
+
+    pub fn union(&self, other: &Bitfield) -> Result<Bitfield, BitfieldError> {
+        self.zip(other, |a, b| a | b)
+    }
+
+    pub fn intersection(&self, other: &Bitfield) -> Result<Bitfield, BitfieldError> {
+        self.zip(other, |a, b| a & b)
+    }
+
+    /// Есть в self, но нет в other: например, куски пира, которых нет у нас
+    pub fn difference(&self, other: &Bitfield) -> Result<Bitfield, BitfieldError> {
+        self.zip(other, |a, b| a & !b)
+    }
+
+    /// Есть ли общий кусок; без промежуточного поля. Поля разной длины не пересекаются.
+    pub fn intersects(&self, other: &Bitfield) -> bool {
+        self.len == other.len && self.bytes.iter().zip(other.bytes.iter()).any(|(&a, &b)| a & b != 0)
+    }
+}
+
+/// Номера битов, отличных от skip-байта (0 - ищем единицы, 0xff - нули)
+fn bits<'a>(bytes: &'a [u8], skip: u8) -> impl Iterator<Item=u32> + 'a {
+    bytes.iter().enumerate()
+        .filter(move |&(_, &byte)| byte != skip)
+        .flat_map(move |(i, &byte)| {
+            let byte = byte ^ skip;
+            (0..8u32).filter(move |bit| byte & (0x80 >> bit) != 0).map(move |bit| i as u32 * 8 + bit)
+        })
+}
+
+impl AsRef<[u8]> for Bitfield {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bitfield() {
+        assert_eq!([0b11111111, 0b11111111], Bitfield::full(16).as_bytes());
+        let mut bitfield = Bitfield::full(19);
+        assert_eq!([0b11111111, 0b11111111, 0b11100000], bitfield.as_bytes());
+        bitfield.clear(10).unwrap(); //помним, что нумерация с нуля
+        assert_eq!([255u8, 0b11011111, 0b11100000], bitfield.as_bytes());
+        assert_eq!((18, 1), (bitfield.count_ones(), bitfield.count_zeros()));
+        assert!(bitfield.set(19).is_err());
+        assert!(bitfield.clear(19).is_err());
+        assert!(!bitfield.get(19));
+
+        let mut bitfield = Bitfield::new(20);
+        assert_eq!([0u8, 0, 0], bitfield.as_bytes());
+        bitfield.set(15).unwrap();
+        assert_eq!([0u8, 1, 0], bitfield.as_bytes());
+        assert!(bitfield.get(15) && !bitfield.get(14));
+
+        let empty = Bitfield::full(0);
+        assert!(empty.is_empty() && empty.is_full());
+        assert!(empty.as_bytes().is_empty());
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        assert!(Bitfield::from_bytes(&[0xff, 0b1110_0000], 11).is_ok());
+        assert!(Bitfield::from_bytes(&[0xff, 0b1111_0000], 11).is_err());
+        assert!(Bitfield::from_bytes(&[0xff], 11).is_err());
+        assert!(Bitfield::from_bytes(&[0xff, 0, 0], 11).is_err());
+        assert!(Bitfield::from_bytes(&[], 0).is_ok());
+    }
+
+    #[test]
+    fn test_iter() {
+        let bitfield = Bitfield::from_bytes(&[0b1000_0001, 0, 0xff, 0b0100_0000], 26).unwrap();
+        assert_eq!(vec![0, 7, 16, 17, 18, 19, 20, 21, 22, 23, 25], bitfield.ones().collect::<Vec<_>>());
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 8, 9, 10, 11, 12, 13, 14, 15, 24], bitfield.zeros().collect::<Vec<_>>());
+
+        let big = Bitfield::full(100_003);
+        assert_eq!(100_003, big.ones().count() as u32);
+        assert_eq!(None, big.zeros().next());
+    }
+
+    #[test]
+    fn test_set_algebra() {
+        let a = Bitfield::from_bytes(&[0b0000_0000, 0b0001_1100, 0b1110_0000], 20).unwrap();
+        let b = Bitfield::from_bytes(&[0b1110_0011, 0b0001_1100, 0b0011_0000], 20).unwrap();
+        assert_eq!([0b1110_0011, 0b0001_1100, 0b1111_0000], a.union(&b).unwrap().as_bytes());
+        assert_eq!([0, 0b0001_1100, 0b0010_0000], a.intersection(&b).unwrap().as_bytes());
+        assert_eq!([0b1110_0011, 0, 0b0001_0000], b.difference(&a).unwrap().as_bytes());
+        assert!(a.union(&Bitfield::new(21)).is_err());
+        assert!(a.intersects(&b) && !a.intersects(&b.difference(&a).unwrap()));
+        assert!(!a.intersects(&Bitfield::full(21)));
+    }
+}
@@ -0,0 +1,106 @@
+//! Сборка куска из блоков, запрошенных у разных пиров
+
+use std::net::SocketAddr;
+
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Block {
+    Missing,
+    Requested(SocketAddr),
+    Received,
+}
+
+pub struct PieceBuffer {
+    size: u32,
+    data: Vec<u8>, //выделяется при первом блоке
+    blocks: Vec<Block>,
+}
+
+impl PieceBuffer {
+    pub fn new(size: u64) -> Self {
+        let size = size as u32;
+        let count = (size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        PieceBuffer { size, data: Vec::new(), blocks: vec![Block::Missing; count as usize] }
+    }
+
+    fn block_length(&self, index: usize) -> u32 {
+        std::cmp::min(BLOCK_SIZE, self.size - index as u32 * BLOCK_SIZE)
+    }
+
+    /// Следующий никем не запрошенный блок: (смещение, длина)
+    pub fn next_missing(&self) -> Option<(u32, u32)> {
+        self.blocks.iter().position(|&block| block == Block::Missing)
+            .map(|index| (index as u32 * BLOCK_SIZE, self.block_length(index)))
+    }
+
+    pub fn requested(&mut self, offset: u32, addr: SocketAddr) {
+        if let Some(block) = self.blocks.get_mut((offset / BLOCK_SIZE) as usize) {
+            *block = Block::Requested(addr);
+        }
+    }
+
+    /// Блоки, запрошенные у отключившегося или зажавшего нас пира, снова свободны
+    pub fn release(&mut self, addr: &SocketAddr) {
+        for block in self.blocks.iter_mut() {
+            if *block == Block::Requested(*addr) {
+                *block = Block::Missing;
+            }
+        }
+    }
+
+    /// false, если блок не на своем месте, не той длины или уже получен
+    pub fn add(&mut self, offset: u32, data: &[u8]) -> bool {
+        let index = (offset / BLOCK_SIZE) as usize;
+        if offset % BLOCK_SIZE != 0 || index >= self.blocks.len() || self.blocks[index] == Block::Received
+            || data.len() != self.block_length(index) as usize {
+            return false;
+        }
+        if self.data.is_empty() {
+            self.data = vec![0u8; self.size as usize];
+        }
+        self.data[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        self.blocks[index] = Block::Received;
+        true
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.blocks.iter().all(|&block| block == Block::Received)
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_piece_buffer() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let mut buffer = PieceBuffer::new(u64::from(BLOCK_SIZE) * 2 + 10);
+        assert_eq!(Some((0, BLOCK_SIZE)), buffer.next_missing());
+        buffer.requested(0, a);
+        buffer.requested(BLOCK_SIZE, b);
+        assert_eq!(Some((BLOCK_SIZE * 2, 10)), buffer.next_missing());
+        buffer.requested(BLOCK_SIZE * 2, a);
+        assert_eq!(None, buffer.next_missing());
+        buffer.release(&a);
+        assert_eq!(Some((0, BLOCK_SIZE)), buffer.next_missing());
+
+        assert!(!buffer.add(1, &[0u8; 10]));
+        assert!(!buffer.add(BLOCK_SIZE * 2, &[0u8; 9]));
+        assert!(buffer.add(BLOCK_SIZE * 2, &[3u8; 10]));
+        assert!(!buffer.add(BLOCK_SIZE * 2, &[3u8; 10]));
+        assert!(buffer.add(0, &vec![1u8; BLOCK_SIZE as usize]));
+        assert!(!buffer.is_complete());
+        assert!(buffer.add(BLOCK_SIZE, &vec![2u8; BLOCK_SIZE as usize]));
+        assert!(buffer.is_complete());
+        let data = buffer.into_data();
+        assert_eq!(BLOCK_SIZE as usize * 2 + 10, data.len());
+        assert_eq!((1, 2, 3), (data[0], data[BLOCK_SIZE as usize], data[data.len() - 1]));
+    }
+}
@@ -0,0 +1,128 @@
+use bip_metainfo::{MetainfoBuilder, MetainfoFile, PieceLength, FileAccessor};
+use bytes::Bytes;
+use futures::Future;
+use futures::sync::oneshot;
+use std::path::{Path, PathBuf};
+use super::TorrentError;
+use super::files::Layout;
+use super::bitfield::Bitfield;
+use super::resume;
+
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+const HASH_THREADS: usize = 2;
+
+#[derive(Debug, Default, Clone)]
+pub struct CreateOptions {
+    pub piece_length: Option<usize>, //None - подобрать автоматически
+    pub trackers: Vec<String>,
+    pub comment: Option<String>,
+    pub private: bool,
+}
+
+impl CreateOptions {
+    pub fn validate(&self) -> Result<(), TorrentError> {
+        if let Some(length) = self.piece_length {
+            if !length.is_power_of_two() || length < MIN_PIECE_LENGTH || length > MAX_PIECE_LENGTH {
+                return Err(TorrentError(format!(
+                    "piece length must be a power of two between {} and {}", MIN_PIECE_LENGTH, MAX_PIECE_LENGTH
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn build(source: &Path, options: &CreateOptions) -> Result<Vec<u8>, TorrentError> {
+    let piece_length = match options.piece_length {
+        Some(length) => PieceLength::Custom(length),
+        None => PieceLength::OptBalanced,
+    };
+    let trackers: Vec<Vec<String>> = options.trackers.iter().map(|t| vec![t.clone()]).collect();
+    let created_by = format!("media-service/{}", env!("CARGO_PKG_VERSION"));
+    let builder = MetainfoBuilder::new()
+        .set_main_tracker(options.trackers.first().map(String::as_str))
+        .set_trackers(if trackers.len() > 1 { Some(&trackers) } else { None })
+        .set_comment(options.comment.as_ref().map(String::as_str))
+        .set_created_by(Some(created_by.as_str()))
+        .set_private_flag(if options.private { Some(true) } else { None })
+        .set_piece_length(piece_length);
+    let accessor = FileAccessor::new(source)
+        .map_err(|e| TorrentError(format!("{}: {}", source.display(), e)))?;
+    builder.build(HASH_THREADS, accessor, |_| {})
+        .map_err(|e| TorrentError(e.to_string()))
+}
+
+/// Создает .torrent из файла или каталога source. Хеширование идет в отдельном потоке.
+/// source должен лежать прямо в каталоге с данными, чтобы раздача нашла файлы.
+pub fn create(source: PathBuf, options: CreateOptions) -> impl Future<Item=Bytes, Error=failure::Error> {
+    let (sender, receiver) = oneshot::channel();
+    std::thread::spawn(move || {
+        let result = options.validate()
+            .and_then(|_| build(&source, &options))
+            .map_err(failure::Error::from)
+            .and_then(|bytes| {
+                mark_complete(&source, &bytes)?;
+                Ok(Bytes::from(bytes))
+            });
+        sender.send(result).ok();
+    });
+    receiver.from_err().and_then(|result| result)
+}
+
+/// Только что захешированные данные заведомо целы: пишем resume, чтобы не перепроверять их при добавлении
+fn mark_complete(source: &Path, bytes: &[u8]) -> Result<(), failure::Error> {
+    let meta = MetainfoFile::from_bytes(bytes).map_err(|e| TorrentError(e.to_string()))?;
+    let root = source.parent()
+        .ok_or_else(|| TorrentError(format!("{} has no parent directory", source.display())))?;
+    let layout = Layout::new(&meta, root);
+    let path = resume::resume_path(root, &hex::encode(meta.info_hash()));
+    resume::save(&path, &layout, &Bitfield::full(layout.pieces_count()), false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use torrent::files::test::temp_dir;
+
+    #[test]
+    fn test_create() {
+        let dir = temp_dir();
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let source = dir.join("clip.mp4");
+        fs::write(&source, &data).unwrap();
+        let options = CreateOptions {
+            piece_length: Some(MIN_PIECE_LENGTH),
+            trackers: vec!["http://tracker/announce".to_string()],
+            ..CreateOptions::default()
+        };
+        let bytes = create(source.clone(), options).wait().unwrap();
+        let meta = MetainfoFile::from_bytes(&bytes).unwrap();
+        assert_eq!(Some("http://tracker/announce"), meta.main_tracker());
+        let info = meta.info();
+        assert_eq!(MIN_PIECE_LENGTH as u64, info.piece_length());
+        let pieces: Vec<Vec<u8>> = info.pieces().map(|p| p.to_vec()).collect();
+        let expected: Vec<Vec<u8>> = data.chunks(MIN_PIECE_LENGTH)
+            .map(|chunk| sha1::Sha1::from(chunk).digest().bytes().to_vec())
+            .collect();
+        assert_eq!(expected, pieces);
+        //данные уже на месте: resume-файл говорит, что все куски есть
+        let layout = Layout::new(&meta, &dir);
+        let resume = resume::load(&resume::resume_path(&dir, &hex::encode(meta.info_hash())), &layout).unwrap();
+        assert!(resume.have.is_full());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_piece_length() {
+        let mut options = CreateOptions::default();
+        assert!(options.validate().is_ok());
+        options.piece_length = Some(256 * 1024);
+        assert!(options.validate().is_ok());
+        options.piece_length = Some(100_000);
+        assert!(options.validate().is_err());
+        options.piece_length = Some(1024);
+        assert!(options.validate().is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use bip_metainfo::InfoHash;
+use bytes::Bytes;
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::{Async, Poll, Stream};
+
+/// События торрента для подписчиков (SSE и т.п.)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    MetadataReceived {
+        name: String,
+        size: u64,
+        pieces: u32,
+        verified_pieces: u32,
+    },
+    PieceVerified {
+        piece: u32,
+        verified_pieces: u32,
+        pieces: u32,
+    },
+    PeerConnected {
+        addr: String,
+        client: Option<String>,
+    },
+    PeerDisconnected {
+        addr: String,
+        reason: String,
+    },
+    TrackerAnnounce {
+        url: String,
+        peers: usize,
+        error: Option<String>,
+        warning: Option<String>,
+    },
+    FileCompleted {
+        index: usize,
+        path: String,
+    },
+    TorrentCompleted,
+    Error {
+        message: String,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::MetadataReceived { .. } => "metadata_received",
+            Event::PieceVerified { .. } => "piece_verified",
+            Event::PeerConnected { .. } => "peer_connected",
+            Event::PeerDisconnected { .. } => "peer_disconnected",
+            Event::TrackerAnnounce { .. } => "tracker_announce",
+            Event::FileCompleted { .. } => "file_completed",
+            Event::TorrentCompleted => "torrent_completed",
+            Event::Error { .. } => "error",
+        }
+    }
+
+    /// Кадр text/event-stream: имя события и json одной строкой
+    pub fn to_sse(&self) -> Bytes {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        Bytes::from(format!("event: {}\ndata: {}\n\n", self.name(), data))
+    }
+}
+
+/// Шина событий сервиса. Живет в потоке сервиса; отписка - просто закрыть receiver.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Vec<(InfoHash, UnboundedSender<Event>)>,
+}
+
+impl EventBus {
+    /// snapshot уходит только новому подписчику: текущее состояние, чтобы не ждать первого события
+    pub fn subscribe(&mut self, info_hash: InfoHash, snapshot: Option<Event>) -> UnboundedReceiver<Event> {
+        let (sender, receiver) = mpsc::unbounded();
+        if let Some(event) = snapshot {
+            sender.unbounded_send(event).ok();
+        }
+        self.subscribers.push((info_hash, sender));
+        receiver
+    }
+
+    pub fn publish(&mut self, info_hash: &InfoHash, event: Event) {
+        self.subscribers.retain(|(hash, sender)| {
+            hash != info_hash || sender.unbounded_send(event.clone()).is_ok()
+        });
+    }
+
+    /// Торрент удален: подписчики получают конец потока
+    pub fn close(&mut self, info_hash: &InfoHash) {
+        self.subscribers.retain(|(hash, _)| hash != info_hash);
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.len()
+    }
+}
+
+/// События вперемешку с keep-alive; кончается вместе с events, keep_alive сам по себе бесконечен
+pub struct KeepAlive<S, K> {
+    events: S,
+    keep_alive: K,
+}
+
+impl<S, K> KeepAlive<S, K> where S: Stream, K: Stream<Item=S::Item, Error=S::Error> {
+    pub fn new(events: S, keep_alive: K) -> Self {
+        KeepAlive { events, keep_alive }
+    }
+}
+
+impl<S, K> Stream for KeepAlive<S, K> where S: Stream, K: Stream<Item=S::Item, Error=S::Error> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        match self.events.poll()? {
+            Async::NotReady => {}
+            ready => return Ok(ready),
+        }
+        match self.keep_alive.poll()? {
+            Async::Ready(Some(item)) => Ok(Async::Ready(Some(item))),
+            //таймер кончился - ждем только события
+            _ => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::Stream;
+
+    #[test]
+    fn test_bus() {
+        let first = InfoHash::from([1u8; 20]);
+        let second = InfoHash::from([2u8; 20]);
+        let mut bus = EventBus::default();
+        let events = bus.subscribe(first, Some(Event::TorrentCompleted));
+        let closed = bus.subscribe(first, None);
+        drop(closed);
+        bus.publish(&second, Event::TorrentCompleted);
+        bus.publish(&first, Event::PieceVerified { piece: 3, verified_pieces: 1, pieces: 10 });
+        assert_eq!(1, bus.subscribers());
+        drop(bus);
+        let received: Vec<Event> = events.wait().map(Result::unwrap).collect();
+        assert_eq!(
+            vec![Event::TorrentCompleted, Event::PieceVerified { piece: 3, verified_pieces: 1, pieces: 10 }],
+            received
+        );
+    }
+
+    #[test]
+    fn test_keep_alive_ends_with_events() {
+        let mut bus = EventBus::default();
+        let info_hash = InfoHash::from([1u8; 20]);
+        let events = bus.subscribe(info_hash, None).map(|event| event.to_sse());
+        let ping = Bytes::from_static(b": keep-alive\n\n");
+        let mut frames = KeepAlive::new(events, futures::stream::repeat(ping.clone())).wait();
+        assert_eq!(Some(Ok(ping)), frames.next());
+        bus.publish(&info_hash, Event::TorrentCompleted);
+        assert_eq!(Some(Ok(Event::TorrentCompleted.to_sse())), frames.next());
+        bus.close(&info_hash);
+        assert_eq!(None, frames.next());
+    }
+
+    #[test]
+    fn test_sse_frame() {
+        assert_eq!(
+            Bytes::from_static(b"event: torrent_completed\ndata: {\"type\":\"torrent_completed\"}\n\n"),
+            Event::TorrentCompleted.to_sse()
+        );
+    }
+}
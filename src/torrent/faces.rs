@@ -1,16 +1,51 @@
 use futures::Stream;
 use bytes::Bytes;
 use futures::Async;
+use std::path::PathBuf;
 
 pub type HashString = [u8; 20];
 
 #[derive(Debug,Fail)]
-#[fail(display="{}",0)]
+#[fail(display="{}",_0)]
 pub struct TorrentError(pub String);
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub data_dir: PathBuf,
+    pub peer_port: u16,
+    pub max_connections: usize,
+    pub max_peers_per_torrent: usize,
+    pub connect_timeout: u64, //секунды
+    pub handshake_timeout: u64,
+    pub download_rate: u64, //байт в секунду, 0 - без ограничений
+    pub upload_rate: u64,
+    pub user_agent: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            data_dir: PathBuf::from("data"),
+            peer_port: 6882,
+            max_connections: 200,
+            max_peers_per_torrent: 50,
+            connect_timeout: 10,
+            handshake_timeout: 10,
+            download_rate: 0,
+            upload_rate: 0,
+            user_agent: format!("media-service/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
 pub trait TorrentClient {
     fn download(&mut self) -> SizedStream;
     fn download_file(&mut self, num: usize) -> SizedStream;
+    /// Часть торрента: length байт начиная с offset
+    fn download_part(&mut self, offset: u64, length: u64) -> SizedStream;
+    /// Часть файла: length байт начиная с offset внутри файла
+    fn download_file_range(&mut self, num: usize, offset: u64, length: u64) -> SizedStream;
 }
 
 pub struct SizedStream {
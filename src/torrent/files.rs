@@ -0,0 +1,261 @@
+use bip_metainfo::MetainfoFile;
+use bytes::{Bytes, BytesMut, BufMut};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use super::{HashString, TorrentError};
+use super::bitfield::Bitfield;
+
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub offset: u64, //смещение файла внутри торрента
+    pub length: u64,
+}
+
+/// Раскладка торрента по файлам на диске: какие куски какого файла касаются.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub piece_length: u64,
+    pub total: u64,
+    pub files: Vec<FileEntry>,
+    pub hashes: Vec<HashString>,
+}
+
+impl Layout {
+    pub fn new(meta: &MetainfoFile, root: &Path) -> Self {
+        let info = meta.info();
+        let root = match info.directory() {
+            Some(dir) => root.join(dir),
+            None => root.to_path_buf(),
+        };
+        let mut offset = 0;
+        let files = info.files().map(|f| {
+            let entry = FileEntry {
+                path: root.join(f.path()),
+                offset,
+                length: f.length(),
+            };
+            offset += f.length();
+            entry
+        }).collect();
+        let hashes = info.pieces().map(|p| {
+            let mut hash: HashString = Default::default();
+            hash.copy_from_slice(p);
+            hash
+        }).collect();
+        Layout {
+            piece_length: info.piece_length(),
+            total: offset,
+            files,
+            hashes,
+        }
+    }
+
+    pub fn pieces_count(&self) -> u32 {
+        self.hashes.len() as u32
+    }
+
+    pub fn piece_offset(&self, index: u32) -> u64 {
+        index as u64 * self.piece_length
+    }
+
+    pub fn piece_size(&self, index: u32) -> u64 {
+        let offset = self.piece_offset(index);
+        if offset >= self.total {
+            0
+        } else {
+            std::cmp::min(self.piece_length, self.total - offset)
+        }
+    }
+
+    /// Куски, в которые попадает диапазон [offset, offset + length) торрента
+    pub fn range_pieces(&self, offset: u64, length: u64) -> Range<u32> {
+        if length == 0 {
+            return 0..0;
+        }
+        let first = (offset / self.piece_length) as u32;
+        let last = ((offset + length - 1) / self.piece_length) as u32;
+        first..last + 1
+    }
+
+    /// Куски, в которые попадает файл с номером index; для пустого файла - пустой диапазон
+    pub fn file_pieces(&self, index: usize) -> Range<u32> {
+        self.files.get(index).map_or(0..0, |file| self.range_pieces(file.offset, file.length))
+    }
+
+    /// Диапазон внутри файла в координатах торрента, обрезанный по размеру файла
+    pub fn file_range(&self, index: usize, offset: u64, length: u64) -> Option<(u64, u64)> {
+        self.files.get(index).map(|file| {
+            let offset = std::cmp::min(offset, file.length);
+            (file.offset + offset, std::cmp::min(length, file.length - offset))
+        })
+    }
+
+    /// Часть куска index, попадающая в [offset, end) торрента: (смещение, длина)
+    pub fn piece_part(&self, index: u32, offset: u64, end: u64) -> (u64, u64) {
+        let piece_offset = self.piece_offset(index);
+        let start = std::cmp::max(offset, piece_offset);
+        let stop = std::cmp::min(end, piece_offset + self.piece_size(index));
+        (start, stop.saturating_sub(start))
+    }
+
+    /// Куски файла: (файл, смещение внутри файла, длина)
+    fn spans(&self, offset: u64, length: u64) -> Vec<(&FileEntry, u64, u64)> {
+        let end = offset + length;
+        self.files.iter()
+            .filter(|f| f.length > 0 && f.offset < end && f.offset + f.length > offset)
+            .map(|f| {
+                let start = std::cmp::max(f.offset, offset);
+                let stop = std::cmp::min(f.offset + f.length, end);
+                (f, start - f.offset, stop - start)
+            })
+            .collect()
+    }
+
+    pub fn read_piece(&self, index: u32) -> io::Result<Bytes> {
+        self.read_range(self.piece_offset(index), self.piece_size(index))
+    }
+
+    /// Байты [offset, offset + length) торрента, например один блок куска
+    pub fn read_range(&self, offset: u64, length: u64) -> io::Result<Bytes> {
+        if offset + length > self.total {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "range is out of torrent"));
+        }
+        let mut buf = BytesMut::with_capacity(length as usize);
+        for (file, offset, length) in self.spans(offset, length) {
+            let mut f = File::open(&file.path)?;
+            f.seek(SeekFrom::Start(offset))?;
+            let mut chunk = vec![0u8; length as usize];
+            f.read_exact(&mut chunk)?;
+            buf.put(chunk);
+        }
+        Ok(buf.freeze())
+    }
+
+    pub fn write_piece(&self, index: u32, data: &[u8]) -> io::Result<()> {
+        let mut data = data;
+        for (file, offset, length) in self.spans(self.piece_offset(index), data.len() as u64) {
+            if let Some(dir) = file.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut f = OpenOptions::new().write(true).create(true).open(&file.path)?;
+            f.seek(SeekFrom::Start(offset))?;
+            let (chunk, rest) = data.split_at(length as usize);
+            f.write_all(chunk)?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    pub fn check_piece(&self, index: u32, data: &[u8]) -> bool {
+        match self.hashes.get(index as usize) {
+            Some(hash) => sha1::Sha1::from(data).digest().bytes() == *hash,
+            None => false,
+        }
+    }
+
+    /// Полная перепроверка всех кусков, лежащих на диске
+    pub fn recheck(&self) -> Bitfield {
+        let mut bitfield = Bitfield::new(self.pieces_count());
+        for index in 0..self.pieces_count() {
+            match self.read_piece(index) {
+                Ok(ref data) if self.check_piece(index, data) => {
+                    bitfield.set(index).ok();
+                }
+                _ => {}
+            }
+        }
+        bitfield
+    }
+}
+
+/// Что торрент занимает в каталоге данных: свой каталог или файлы. Пути с ".." и т.п. не принимаются.
+pub fn content_paths(meta: &MetainfoFile, root: &Path) -> Result<Vec<PathBuf>, TorrentError> {
+    let info = meta.info();
+    let relative: Vec<PathBuf> = match info.directory() {
+        Some(dir) => vec![dir.to_path_buf()],
+        None => info.files().map(|f| f.path().to_path_buf()).collect(),
+    };
+    relative.into_iter()
+        .map(|path| {
+            let plain = path.components().count() > 0
+                && path.components().all(|c| match c { Component::Normal(_) => true, _ => false });
+            if plain {
+                Ok(root.join(path))
+            } else {
+                Err(TorrentError(format!("unsafe path {} in torrent", path.display())))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    pub fn make_layout(root: &Path, piece_length: u64, files: &[(&str, &[u8])]) -> Layout {
+        let data: Vec<u8> = files.iter().flat_map(|(_, d)| d.iter().cloned()).collect();
+        let mut offset = 0;
+        let entries = files.iter().map(|(name, d)| {
+            let entry = FileEntry { path: root.join(name), offset, length: d.len() as u64 };
+            offset += d.len() as u64;
+            entry
+        }).collect();
+        let hashes = data.chunks(piece_length as usize)
+            .map(|chunk| sha1::Sha1::from(chunk).digest().bytes())
+            .collect();
+        Layout { piece_length, total: offset, files: entries, hashes }
+    }
+
+    pub fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_pieces_across_files() {
+        let dir = temp_dir();
+        let layout = make_layout(&dir, 4, &[("a", b"012345"), ("b", b"6789")]);
+        assert_eq!(3, layout.pieces_count());
+        assert_eq!(2, layout.piece_size(2));
+        layout.write_piece(1, b"4567").unwrap();
+        layout.write_piece(0, b"0123").unwrap();
+        layout.write_piece(2, b"89").unwrap();
+        assert_eq!(b"012345".as_ref(), fs::read(dir.join("a")).unwrap().as_slice());
+        assert_eq!(b"6789".as_ref(), fs::read(dir.join("b")).unwrap().as_slice());
+        assert_eq!(b"4567".as_ref(), layout.read_piece(1).unwrap().as_ref());
+        assert_eq!(b"5678".as_ref(), layout.read_range(5, 4).unwrap().as_ref());
+        assert_eq!(b"9".as_ref(), layout.read_range(9, 1).unwrap().as_ref());
+        assert!(layout.read_range(9, 2).is_err());
+        assert_eq!(Bitfield::full(3), layout.recheck());
+        assert_eq!(0..2, layout.file_pieces(0));
+        assert_eq!(1..3, layout.file_pieces(1));
+        assert_eq!(0..0, layout.file_pieces(2));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_ranges() {
+        let dir = PathBuf::from("/nonexistent");
+        let layout = make_layout(&dir, 4, &[("a", b"012345"), ("b", b""), ("c", b"6789ab")]);
+        assert_eq!(0..3, layout.range_pieces(0, 12));
+        assert_eq!(1..3, layout.range_pieces(5, 4)); //с середины куска 1 до середины куска 2
+        assert_eq!(2..3, layout.range_pieces(8, 1));
+        assert_eq!(0..0, layout.range_pieces(5, 0));
+
+        //части кусков для стрима [5, 9)
+        assert_eq!((5, 3), layout.piece_part(1, 5, 9));
+        assert_eq!((8, 1), layout.piece_part(2, 5, 9));
+        assert_eq!((0, 4), layout.piece_part(0, 0, 12));
+        assert_eq!((8, 4), layout.piece_part(2, 0, 100));
+
+        assert_eq!(Some((7, 5)), layout.file_range(2, 1, 100));
+        assert_eq!(Some((8, 2)), layout.file_range(2, 2, 2));
+        assert_eq!(Some((12, 0)), layout.file_range(2, 10, 5));
+        assert_eq!(Some((6, 0)), layout.file_range(1, 0, 5));
+        assert_eq!(None, layout.file_range(3, 0, 1));
+    }
+}
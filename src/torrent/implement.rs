@@ -1,182 +1,875 @@
 
-
 use bip_metainfo::MetainfoFile;
 use super::*;
 use actix_web::client;
+use actix_web::actix::{self, Arbiter};
 use futures::future::Future;
 use actix_web::HttpMessage;
 use bytes::Bytes;
-use futures::sync::mpsc;
-use futures::sync::mpsc::{Sender, Receiver};
+use futures::sync::{mpsc, oneshot};
+use futures::sync::mpsc::UnboundedSender;
 use futures::Stream;
-use futures::Async;
-use self::tokio_core::reactor::Core;
-use self::tokio_core::net::TcpStream;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use bip_metainfo::InfoHash;
-use std::net::IpAddr;
-use std::cell::RefCell;
-use futures::sink::Sink;
-use std::mem;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
 use std::collections::VecDeque;
-use self::peer::Peer;
-
-struct Block;
+use self::peer::{Peer, PeerEvent, Session, Timeouts};
+use self::files::Layout;
+use self::message::{Extensions, Handshake, PeerMessage};
+use self::bitfield::Bitfield;
+use self::blocks::PieceBuffer;
+use super::tokio::timer::{Delay, Interval, Timeout};
+use self::tracker::{AnnounceRequest, AnnounceResponse, TrackerEvent};
+use self::announce::Announcer;
+use self::limit::{RateLimiter, RateLimits, LimitsUpdate, Priority, Direction};
+use std::time::{Duration, Instant};
+use self::events::{Event, EventBus};
+use futures::sync::mpsc::UnboundedReceiver;
+use self::stats::{Counters, TorrentStats, PeerCounts, PeerInfo};
+use std::path::{Path, PathBuf};
+use metrics::Metrics;
+
+const PIPELINE: usize = 16; //блоков, запрошенных у одного пира
+const MAX_REQUEST: u32 = 128 * 1024; //обычно просят по 16 КиБ
+const TICK: Duration = Duration::from_secs(1); //проверка расписания анонсов
+const LOW_PEERS: usize = 5; //меньше - просим у трекеров пиров раньше срока
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
 
 struct TorrentService {
+    settings: Arc<Settings>,
+    commands: UnboundedSender<Command>, //сюда же пишут сессии пиров
     peer_id: HashString,
     uploaded: u64,
     downloaded: u64,
     torrents: HashMap<InfoHash,TorrentConnection>,
+    limiter: RateLimiter,
+    events: EventBus,
+    metrics: Arc<Metrics>,
 }
 
+pub enum Command {
+    Add(MetainfoFile),
+    Schedule {
+        info_hash: InfoHash,
+        pieces: Vec<u32>,
+    },
+    Wait {
+        info_hash: InfoHash,
+        piece: u32,
+        sender: oneshot::Sender<()>,
+    },
+    /// info_hash: None - общие лимиты; update: None - только узнать текущие
+    Limits {
+        info_hash: Option<InfoHash>,
+        update: Option<LimitsUpdate>,
+        sender: oneshot::Sender<RateLimits>,
+    },
+    /// None, если торрент не запущен
+    Stats {
+        info_hash: InfoHash,
+        sender: oneshot::Sender<Option<TorrentStats>>,
+    },
+    /// None, если торрент не запущен
+    Peers {
+        info_hash: InfoHash,
+        sender: oneshot::Sender<Option<Vec<PeerInfo>>>,
+    },
+    /// Остановить торрент и удалить resume, а с delete_data - и скачанные файлы
+    Remove {
+        meta: MetainfoFile,
+        delete_data: bool,
+        sender: oneshot::Sender<Result<(), TorrentError>>,
+    },
+    /// None, если торрент не запущен
+    Subscribe {
+        info_hash: InfoHash,
+        sender: oneshot::Sender<Option<UnboundedReceiver<Event>>>,
+    },
+    PeerConnected {
+        info_hash: InfoHash,
+        addr: SocketAddr,
+        result: Result<Peer, String>,
+    },
+    /// Событие сессии пира
+    Peer {
+        info_hash: InfoHash,
+        addr: SocketAddr,
+        event: PeerEvent,
+    },
+    /// Повторить запросы блоков, когда лимит скорости позволит
+    Pump {
+        info_hash: InfoHash,
+    },
+    Tick,
+    Announced {
+        info_hash: InfoHash,
+        url: String,
+        result: Result<AnnounceResponse, String>,
+    },
+    /// Сообщить трекерам stopped перед выходом
+    Shutdown {
+        sender: oneshot::Sender<()>,
+    },
+}
 
-fn new_service() -> Sender<TorrentRequest> {
-    let service = RefCell::new(TorrentService::new());
-    let (s,r) = mpsc::channel::<TorrentRequest>(100);
-    let mut core = Core::new().unwrap();
-    let handle = core.handle();
-    let runner = r.for_each(|req|{
-        use self::tracker::*;
-        let handle = handle.clone();
-        let TorrentRequest{ meta, filenum: file, sender, receiver} = req;
+#[derive(Clone)]
+pub struct Service {
+    sender: UnboundedSender<Command>,
+    settings: Arc<Settings>,
+}
 
-        let processor = receiver.for_each(move |_| {
-            let sender = sender.clone();
+impl Service {
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+    pub fn send(&self, cmd: Command) -> Result<(), TorrentError> {
+        self.sender.unbounded_send(cmd)
+            .map_err(|_| TorrentError("torrent service is down".to_string()))
+    }
+}
 
-            futures::future::ok(())
-        });
-        handle.spawn(processor);
-        futures::future::ok(())
+pub fn new_service(settings: Settings, metrics: Arc<Metrics>) -> Service {
+    let settings = Arc::new(settings);
+    let (s,r) = mpsc::unbounded::<Command>();
+    let service_settings = settings.clone();
+    let commands = s.clone();
+    std::thread::spawn(move || {
+        let sys = actix::System::new("torrent-service");
+        let ticks = commands.clone();
+        Arbiter::spawn(Interval::new(Instant::now() + TICK, TICK).map_err(|_| ()).for_each(move |_| {
+            ticks.unbounded_send(Command::Tick).map_err(|_| ())
+        }));
+        let mut service = TorrentService::new(service_settings, metrics, commands);
+        Arbiter::spawn(r.for_each(move |cmd| {
+            service.process(cmd);
+            Ok(())
+        }));
+        sys.run();
     });
-    core.run(runner).unwrap();
-    s
+    Service { sender: s, settings }
 }
 
 impl TorrentService {
-    fn new() -> Self {
-        unimplemented!()
-    }
-    fn new_torrent(&mut self, req: &TorrentRequest) {
-        let meta = &req.meta;
-        let announce = meta.main_tracker()
-            .ok_or(TorrentError("announce url not found in .torrent file".to_string())).unwrap().to_string();
-        let info_hash = percent_encoding::percent_encode(meta.info_hash().as_ref(), percent_encoding::PATH_SEGMENT_ENCODE_SET).to_string();
-        let peer_id = percent_encoding::percent_encode(&self.peer_id, percent_encoding::PATH_SEGMENT_ENCODE_SET).to_string();
-        let port = 6882;
-        let uploaded = self.uploaded;
-        let downloaded = self.downloaded;
-        let left = 99999; //TODO: надо вытащить размер файла из меты
-        let event = "started";
-        let uri = format!("{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&event={}",
-                          announce,
-                          info_hash,
-                          peer_id,
-                          port,
-                          uploaded,
-                          downloaded,
-                          left,
-                          event
-        );
-        let _tracker_response = client::get(uri)   // <- Create request builder
-            .header("User-Agent", "Actix-web")
-            .finish().unwrap()
-            .send()                               // <- Send http request
-            .map_err(|e| panic!("Error: {:?}", e))
-            .and_then(|response| response.body())
-            .map_err(|e|panic!("Error: {:?}", e));
+    fn new(settings: Arc<Settings>, metrics: Arc<Metrics>, commands: UnboundedSender<Command>) -> Self {
+        TorrentService {
+            commands,
+            peer_id: peer_id::generate(),
+            uploaded: 0,
+            downloaded: 0,
+            torrents: HashMap::new(),
+            limiter: RateLimiter::new(RateLimits {
+                download_rate: settings.download_rate,
+                upload_rate: settings.upload_rate,
+            }),
+            events: EventBus::default(),
+            metrics,
+            settings,
+        }
+    }
+    fn process(&mut self, cmd: Command) {
+        match cmd {
+            Command::Add(meta) => {
+                if !self.torrents.contains_key(&meta.info_hash()) {
+                    self.new_torrent(meta);
+                }
+            }
+            Command::Schedule { info_hash, pieces } => {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    torrent.schedule(pieces);
+                }
+                self.pump(&info_hash);
+            }
+            Command::Wait { info_hash, piece, sender } => {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    torrent.wait(piece, sender);
+                }
+                self.pump(&info_hash);
+            }
+            Command::Limits { info_hash, update, sender } => {
+                let limits = match (info_hash, update) {
+                    (None, None) => self.limiter.global(),
+                    (None, Some(update)) => self.limiter.set_global(&update),
+                    (Some(info_hash), None) => self.limiter.torrent(&info_hash),
+                    (Some(info_hash), Some(update)) => self.limiter.set_torrent(info_hash, &update),
+                };
+                sender.send(limits).ok();
+            }
+            Command::Stats { info_hash, sender } => {
+                let stats = self.torrents.get_mut(&info_hash).map(|torrent| torrent.stats(Instant::now()));
+                sender.send(stats).ok();
+            }
+            Command::Peers { info_hash, sender } => {
+                let peers = self.torrents.get(&info_hash)
+                    .map(|torrent| torrent.connections.values().map(Peer::info).collect());
+                sender.send(peers).ok();
+            }
+            Command::Remove { meta, delete_data, sender } => {
+                sender.send(self.remove_torrent(&meta, delete_data)).ok();
+            }
+            Command::Subscribe { info_hash, sender } => {
+                let snapshot = self.torrents.get(&info_hash).map(TorrentConnection::metadata_event);
+                let receiver = snapshot.map(|event| self.events.subscribe(info_hash, Some(event)));
+                sender.send(receiver).ok();
+            }
+            Command::PeerConnected { info_hash, addr, result } => self.on_connected(info_hash, addr, result),
+            Command::Peer { info_hash, addr, event } => self.on_peer_event(info_hash, addr, event),
+            Command::Pump { info_hash } => {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    torrent.pump_scheduled = false;
+                }
+                self.pump(&info_hash);
+            }
+            Command::Tick => self.announce_due(Instant::now()),
+            Command::Announced { info_hash, url, result } => self.on_announced(info_hash, url, result),
+            Command::Shutdown { sender } => self.shutdown(sender),
+        }
+    }
+    /// Подключение к пиру; сессия пишет свои события в общий канал команд
+    fn connect_peer(&mut self, info_hash: InfoHash, addr: SocketAddr) {
+        let connections: usize = self.torrents.values().map(TorrentConnection::peer_count).sum();
+        let torrent = match self.torrents.get_mut(&info_hash) {
+            Some(torrent) => torrent,
+            None => return,
+        };
+        if connections >= self.settings.max_connections
+            || torrent.peer_count() >= self.settings.max_peers_per_torrent
+            || torrent.connections.contains_key(&addr)
+            || !torrent.connecting.insert(addr) {
+            return;
+        }
+        let mut hash: HashString = Default::default();
+        hash.copy_from_slice(info_hash.as_ref());
+        let handshake = Handshake::new(hash, self.peer_id, Extensions::default());
+        let pieces = torrent.layout.pieces_count();
+        let commands = self.commands.clone();
+        Arbiter::spawn(peer::connect(addr, handshake, &self.settings).then(move |result| {
+            let result = result.map(|(theirs, stream)| {
+                let (sender, receiver) = mpsc::unbounded();
+                let (events, peer_events) = mpsc::unbounded();
+                Arbiter::spawn(Session::new(stream, pieces, receiver, events, Timeouts::default()));
+                let forward = commands.clone();
+                Arbiter::spawn(peer_events.for_each(move |event| {
+                    forward.unbounded_send(Command::Peer { info_hash, addr, event }).map_err(|_| ())
+                }));
+                Peer::new(addr, &theirs, Extensions::default(), pieces, sender)
+            }).map_err(|e| e.to_string());
+            commands.unbounded_send(Command::PeerConnected { info_hash, addr, result }).ok();
+            Ok(())
+        }));
+    }
+    fn on_connected(&mut self, info_hash: InfoHash, addr: SocketAddr, result: Result<Peer, String>) {
+        match self.torrents.get_mut(&info_hash) {
+            Some(torrent) => {
+                torrent.connecting.remove(&addr);
+                if let Ok(peer) = result {
+                    self.metrics.connected_peers.inc();
+                    torrent.add_peer(peer, &mut self.events);
+                }
+            }
+            None => return,
+        }
+        self.pump(&info_hash);
+    }
+    fn on_peer_event(&mut self, info_hash: InfoHash, addr: SocketAddr, event: PeerEvent) {
+        let message = match event {
+            PeerEvent::Message(message) => message,
+            PeerEvent::Disconnected(reason) => {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    if torrent.remove_peer(&addr, reason, &mut self.events) {
+                        self.metrics.connected_peers.dec();
+                    }
+                }
+                self.pump(&info_hash);
+                return;
+            }
+        };
+        if let PeerMessage::Request { block, offset, length } = message {
+            self.upload(&info_hash, addr, block, offset, length);
+            return;
+        }
+        let completed = match self.torrents.get_mut(&info_hash) {
+            Some(torrent) => torrent.on_message(addr, message),
+            None => return,
+        };
+        if let Some((index, data)) = completed {
+            if let Ok(true) = self.on_piece(&info_hash, index, &data) {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    torrent.broadcast(PeerMessage::Have(index));
+                }
+            }
+        }
+        self.pump(&info_hash);
+    }
+    /// Отдаем блок, если он у нас есть и пир не зажат. Запрос сверх лимита скорости отбрасываем -
+    /// пир его повторит.
+    fn upload(&mut self, info_hash: &InfoHash, addr: SocketAddr, piece: u32, offset: u32, length: u32) {
+        let allowed = match self.torrents.get(info_hash) {
+            Some(torrent) => length <= MAX_REQUEST && torrent.have.get(piece)
+                && u64::from(offset) + u64::from(length) <= torrent.layout.piece_size(piece)
+                && torrent.connections.get(&addr).map_or(false, |peer| !peer.state().am_choking),
+            None => false,
+        };
+        if !allowed || self.throttle(info_hash, length as usize).is_err() {
+            return;
+        }
+        if let Some(torrent) = self.torrents.get_mut(info_hash) {
+            let start = torrent.layout.piece_offset(piece) + u64::from(offset);
+            let data = match torrent.layout.read_range(start, u64::from(length)) {
+                Ok(data) => data,
+                Err(_) => return,
+            };
+            if let Some(peer) = torrent.connections.get_mut(&addr) {
+                peer.send(PeerMessage::Piece { block: piece, offset, data });
+                torrent.counters.on_upload(u64::from(length), Instant::now());
+                self.uploaded += u64::from(length);
+                self.metrics.bytes_out.add(u64::from(length));
+            }
+        }
+    }
+    /// Запросы блоков; если уперлись в лимит скорости - повтор, когда он позволит
+    fn pump(&mut self, info_hash: &InfoHash) {
+        let torrent = match self.torrents.get_mut(info_hash) {
+            Some(torrent) => torrent,
+            None => return,
+        };
+        if let Some(wait) = torrent.request_blocks(&mut self.limiter) {
+            if !torrent.pump_scheduled {
+                torrent.pump_scheduled = true;
+                let commands = self.commands.clone();
+                let info_hash = *info_hash;
+                Arbiter::spawn(Delay::new(Instant::now() + wait).then(move |_| {
+                    commands.unbounded_send(Command::Pump { info_hash }).ok();
+                    Ok(())
+                }));
+            }
+        }
+    }
+    /// Пропускает отдачу bytes байт или говорит, сколько ждать. Скачивание лимитирует request_blocks.
+    fn throttle(&mut self, info_hash: &InfoHash, bytes: usize) -> Result<(), Duration> {
+        self.limiter.acquire(info_hash, Direction::Upload, bytes, Priority::Normal, Instant::now())
+    }
+    fn on_piece(&mut self, info_hash: &InfoHash, index: u32, data: &[u8]) -> Result<bool, failure::Error> {
+        match self.torrents.get_mut(info_hash) {
+            Some(torrent) => {
+                self.metrics.bytes_in.add(data.len() as u64);
+                let accepted = torrent.on_piece(index, data, &mut self.events)?;
+                if accepted {
+                    self.downloaded += data.len() as u64;
+                } else {
+                    self.metrics.hash_failures.inc();
+                }
+                Ok(accepted)
+            }
+            None => Ok(false),
+        }
+    }
+    /// Ждущие стримы получат ошибку: их oneshot-отправители удаляются вместе с торрентом
+    fn remove_torrent(&mut self, meta: &MetainfoFile, delete_data: bool) -> Result<(), TorrentError> {
+        let info_hash = meta.info_hash();
+        if let Some(torrent) = self.torrents.remove(&info_hash) {
+            //сессии закроются сами, когда пропадут их каналы команд
+            self.metrics.connected_peers.sub(torrent.connections.len() as i64);
+            for stop in self.stop_announces(&torrent) {
+                Arbiter::spawn(stop);
+            }
+        }
+        self.limiter.remove_torrent(&info_hash);
+        self.events.close(&info_hash);
+        self.metrics.active_torrents.set(self.torrents.len() as i64);
+        let root = &self.settings.data_dir;
+        let error = |path: &Path, e: std::io::Error| TorrentError(format!("can't remove {}: {}", path.display(), e));
+        let resume = resume::resume_path(root, &hex::encode(info_hash));
+        if resume.exists() {
+            std::fs::remove_file(&resume).map_err(|e| error(&resume, e))?;
+        }
+        if delete_data {
+            for path in files::content_paths(meta, root)? {
+                if path.is_dir() {
+                    std::fs::remove_dir_all(&path).map_err(|e| error(&path, e))?;
+                } else if path.exists() {
+                    std::fs::remove_file(&path).map_err(|e| error(&path, e))?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn new_torrent(&mut self, meta: MetainfoFile) {
+        let connection = TorrentConnection::new(meta, &self.settings.data_dir);
+        let info_hash = connection.meta.info_hash();
+        self.events.publish(&info_hash, connection.metadata_event());
+        self.torrents.insert(info_hash, connection);
+        self.metrics.active_torrents.set(self.torrents.len() as i64);
+        self.announce_due(Instant::now());
+    }
+    fn announce_request(&self, torrent: &TorrentConnection, event: Option<TrackerEvent>, tracker_id: Option<&str>)
+        -> AnnounceRequest {
+        let mut info_hash: HashString = Default::default();
+        info_hash.copy_from_slice(torrent.meta.info_hash().as_ref());
+        AnnounceRequest {
+            info_hash,
+            peer_id: self.peer_id,
+            port: self.settings.peer_port,
+            uploaded: torrent.counters.uploaded,
+            downloaded: torrent.counters.downloaded,
+            left: torrent.left(),
+            event,
+            numwant: None,
+            key: None,
+            tracker_id: tracker_id.map(ToString::to_string),
+        }
+    }
+    /// Анонсы, которым подошел срок, по всем трекерам всех торрентов
+    fn announce_due(&mut self, now: Instant) {
+        let mut due = Vec::new();
+        for (info_hash, torrent) in self.torrents.iter_mut() {
+            //сиду новые пиры ни к чему: они придут сами
+            let want_peers = torrent.peer_count() < LOW_PEERS && !torrent.have.is_full();
+            for announcer in torrent.announcers.iter_mut().filter(|announcer| announcer.is_due(now, want_peers)) {
+                let event = announcer.start(now);
+                due.push((*info_hash, announcer.url().to_string(), event, announcer.tracker_id().map(ToString::to_string)));
+            }
+        }
+        for (info_hash, url, event, tracker_id) in due {
+            let request = match self.torrents.get(&info_hash) {
+                Some(torrent) => self.announce_request(torrent, event, tracker_id.as_ref().map(String::as_str)),
+                None => continue,
+            };
+            let commands = self.commands.clone();
+            Arbiter::spawn(announce(&request.url(&url), &self.settings.user_agent).then(move |result| {
+                commands.unbounded_send(Command::Announced { info_hash, url, result }).ok();
+                Ok(())
+            }));
+        }
+    }
+    fn on_announced(&mut self, info_hash: InfoHash, url: String, result: Result<AnnounceResponse, String>) {
+        let now = Instant::now();
+        let peers = match self.torrents.get_mut(&info_hash) {
+            Some(torrent) => {
+                let announcer = match torrent.announcers.iter_mut().find(|announcer| announcer.url() == url) {
+                    Some(announcer) => announcer,
+                    None => return,
+                };
+                if let Some(latency) = announcer.elapsed(now) {
+                    self.metrics.tracker_latency.observe(latency);
+                }
+                let pending = announcer.is_completion_pending();
+                let result = match result {
+                    Ok(response) => announcer.on_response(now, response),
+                    Err(e) => Err(announcer.on_failure(now, e)),
+                };
+                let completion_sent = pending && !announcer.is_completion_pending();
+                let warning = announcer.stats(now).warning;
+                let (peers, error) = match result {
+                    Ok(peers) => (peers, None),
+                    Err(e) => {
+                        self.metrics.tracker_errors.inc();
+                        (Vec::new(), Some(e))
+                    }
+                };
+                torrent.peers.extend(peers.iter().cloned());
+                self.events.publish(&info_hash, Event::TrackerAnnounce { url, peers: peers.len(), error, warning });
+                if completion_sent && !torrent.completion_pending() {
+                    if let Err(e) = torrent.save_resume() {
+                        self.events.publish(&info_hash, Event::Error { message: format!("can't save resume: {}", e) });
+                    }
+                }
+                peers
+            }
+            None => return,
+        };
+        for addr in peers {
+            self.connect_peer(info_hash, addr);
+        }
+    }
+    /// stopped трекерам, которые знают о торренте; ответ не важен
+    fn stop_announces(&self, torrent: &TorrentConnection) -> Vec<Box<Future<Item=(), Error=()>>> {
+        torrent.announcers.iter().filter(|announcer| announcer.is_started()).map(|announcer| {
+            let request = self.announce_request(torrent, Some(TrackerEvent::Stopped), announcer.tracker_id());
+            Box::new(announce(&request.url(announcer.url()), &self.settings.user_agent).then(|_| Ok(())))
+                as Box<Future<Item=(), Error=()>>
+        }).collect()
+    }
+    /// Перед выходом: stopped по всем торрентам, ждем ответов не дольше STOP_TIMEOUT
+    fn shutdown(&mut self, sender: oneshot::Sender<()>) {
+        let stops: Vec<_> = self.torrents.values().flat_map(|torrent| self.stop_announces(torrent)).collect();
+        Arbiter::spawn(Timeout::new(futures::future::join_all(stops), STOP_TIMEOUT).then(move |_| {
+            sender.send(()).ok();
+            Ok(())
+        }));
     }
 }
 
-pub struct TorrentClient {
-
+/// GET к трекеру; ошибка - строкой, ее покажут статистика и события
+fn announce(url: &str, user_agent: &str) -> Box<Future<Item=AnnounceResponse, Error=String>> {
+    let request = match client::get(url).header("User-Agent", user_agent).finish() {
+        Ok(request) => request,
+        Err(e) => return Box::new(futures::failed(e.to_string())),
+    };
+    Box::new(request.send()
+        .timeout(ANNOUNCE_TIMEOUT)
+        .map_err(|e| e.to_string())
+        .and_then(|response| {
+            let status = response.status();
+            response.body().map_err(|e| e.to_string()).and_then(move |body| {
+                if status.is_success() {
+                    Ok(AnnounceResponse::from(body))
+                } else {
+                    Err(format!("tracker responded with {}", status))
+                }
+            })
+        }))
 }
 
-
-struct TorrentRequest {
-    meta: MetainfoFile,
-    filenum: usize, //номер файла в торрент-файле
-    sender: Sender<Bytes>,
-    receiver: Receiver<()>, // когда у нас дернется receiver нужно будет послать байты в sender
+/// announce и announce-list без повторов; udp-трекеры мы не поддерживаем
+fn tracker_urls(meta: &MetainfoFile) -> Vec<String> {
+    let list = meta.trackers().into_iter().flat_map(|tiers| tiers.iter().flat_map(|tier| tier.iter()));
+    let mut urls: Vec<String> = Vec::new();
+    for url in meta.main_tracker().into_iter().chain(list.map(String::as_str)) {
+        if (url.starts_with("http://") || url.starts_with("https://")) && !urls.iter().any(|known| known == url) {
+            urls.push(url.to_string());
+        }
+    }
+    urls
 }
 
 struct TorrentConnection {
-    client: RefCell<TorrentService>,
-    request: TorrentRequest,
-    peers: Vec<IpAddr>,
-    connections: Vec<Peer>,
-    cache: VecDeque<Bytes>,
+    meta: MetainfoFile,
+    peers: HashSet<SocketAddr>, //известные от трекеров
+    announcers: Vec<Announcer>,
+    connections: HashMap<SocketAddr, Peer>,
+    connecting: HashSet<SocketAddr>,
+    downloads: HashMap<u32, PieceBuffer>, //куски, собираемые из блоков
+    pump_scheduled: bool,
+    layout: Layout,
+    have: Bitfield, //проверенные куски
+    resume: PathBuf,
+    wanted: VecDeque<u32>, //куски в порядке, в котором их ждут стримы; скачанные и повторы убираются лениво
+    scheduled: Bitfield, //что из wanted еще нужно
+    waiters: HashMap<u32, Vec<oneshot::Sender<()>>>,
+    counters: Counters,
 }
 
 impl TorrentConnection {
-    //три сервиса:
-    // один перидочески обращается к торрент-трекеру и обрабатывает его ответы,
-    // второй обрабатывает ответы от присоединенных пиров,
-    // третий обрабатывает
-    fn update_torrent_info(&mut self) {
-
+    fn new(meta: MetainfoFile, root: &Path) -> Self {
+        let layout = Layout::new(&meta, root);
+        let resume = resume::resume_path(root, &hex::encode(meta.info_hash()));
+        let restored = resume::restore(&resume, &layout);
+        let scheduled = Bitfield::new(layout.pieces_count());
+        let mut announcers: Vec<Announcer> =
+            tracker_urls(&meta).into_iter().map(|url| Announcer::new(url, Instant::now())).collect();
+        if restored.completed {
+            for announcer in announcers.iter_mut() {
+                announcer.complete();
+            }
+        }
+        TorrentConnection {
+            meta,
+            peers: HashSet::new(),
+            announcers,
+            connections: HashMap::new(),
+            connecting: HashSet::new(),
+            downloads: HashMap::new(),
+            pump_scheduled: false,
+            layout,
+            have: restored.have,
+            resume,
+            wanted: VecDeque::new(),
+            scheduled,
+            waiters: HashMap::new(),
+            counters: Counters::new(Instant::now()),
+        }
     }
-    fn process_peer(&mut self) {
-
+    fn metadata_event(&self) -> Event {
+        let info = self.meta.info();
+        let name = match info.directory() {
+            Some(dir) => dir.to_string_lossy().into_owned(),
+            None => info.files().next().map(|f| f.path().to_string_lossy().into_owned()).unwrap_or_default(),
+        };
+        Event::MetadataReceived {
+            name,
+            size: self.layout.total,
+            pieces: self.layout.pieces_count(),
+            verified_pieces: self.verified_pieces(),
+        }
     }
-    fn process_download(&mut self) {
-        let cache = self.cache.pop_front().unwrap();
-        self.request.sender.try_send(cache).unwrap();
-
+    fn on_piece(&mut self, index: u32, data: &[u8], events: &mut EventBus) -> Result<bool, failure::Error> {
+        let info_hash = self.meta.info_hash();
+        if !self.layout.check_piece(index, data) {
+            self.counters.on_wasted(data.len() as u64, Instant::now());
+            events.publish(&info_hash, Event::Error { message: format!("piece {} failed hash check", index) });
+            return Ok(false);
+        }
+        self.counters.on_download(data.len() as u64, Instant::now());
+        //повторно скачанный кусок ничего не завершает
+        let new_piece = !self.have.get(index);
+        if let Err(e) = self.store_piece(index, data) {
+            events.publish(&info_hash, Event::Error { message: format!("can't store piece {}: {}", index, e) });
+            return Err(e);
+        }
+        self.scheduled.clear(index).ok();
+        while self.wanted.front().map_or(false, |&piece| !self.scheduled.get(piece)) {
+            self.wanted.pop_front();
+        }
+        for sender in self.waiters.remove(&index).unwrap_or_default() {
+            sender.send(()).ok();
+        }
+        events.publish(&info_hash, Event::PieceVerified {
+            piece: index,
+            verified_pieces: self.verified_pieces(),
+            pieces: self.layout.pieces_count(),
+        });
+        if !new_piece {
+            return Ok(true);
+        }
+        for (file_index, file) in self.layout.files.iter().enumerate() {
+            let mut pieces = self.layout.file_pieces(file_index);
+            if pieces.start <= index && index < pieces.end && pieces.all(|i| self.have.get(i)) {
+                events.publish(&info_hash, Event::FileCompleted {
+                    index: file_index,
+                    path: file.path.to_string_lossy().into_owned(),
+                });
+            }
+        }
+        if self.have.is_full() {
+            for announcer in self.announcers.iter_mut() {
+                announcer.complete();
+            }
+            if let Err(e) = self.save_resume() {
+                events.publish(&info_hash, Event::Error { message: format!("can't save resume: {}", e) });
+            }
+            events.publish(&info_hash, Event::TorrentCompleted);
+        }
+        Ok(true)
     }
-
-}
-
-
-struct TorrentStream {
-    sender: Sender<()>,
-    receiver: Receiver<Bytes>
-}
-
-impl Stream for TorrentStream {
-    type Item = Bytes;
-    type Error = ();
-
-    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        match self.receiver.poll() {
-            Ok(Async::NotReady) => Ok(Async::NotReady),
-            ret @ Ok(Async::Ready(None)) => ret, //в каком случае придет none?
-            ret @ Ok(Async::Ready(Some(_))) => {
-                self.sender.try_send(()).unwrap();
-                ret
+    fn store_piece(&mut self, index: u32, data: &[u8]) -> Result<(), failure::Error> {
+        self.layout.write_piece(index, data)?;
+        self.have.set(index)?;
+        self.save_resume()
+    }
+    fn save_resume(&self) -> Result<(), failure::Error> {
+        resume::save(&self.resume, &self.layout, &self.have, self.completion_pending())
+    }
+    /// Хоть один трекер еще не знает, что мы докачали
+    fn completion_pending(&self) -> bool {
+        self.announcers.iter().any(Announcer::is_completion_pending)
+    }
+    fn schedule(&mut self, pieces: Vec<u32>) {
+        for piece in pieces {
+            if !self.have.get(piece) && !self.scheduled.get(piece) && self.scheduled.set(piece).is_ok() {
+                self.wanted.push_back(piece);
+            }
+        }
+    }
+    fn wait(&mut self, piece: u32, sender: oneshot::Sender<()>) {
+        if self.have.get(piece) {
+            sender.send(()).ok();
+            return;
+        }
+        //кусок, который ждут прямо сейчас, качаем в первую очередь
+        if self.scheduled.set(piece).is_ok() && self.wanted.front() != Some(&piece) {
+            self.wanted.push_front(piece); //прежнее место в очереди останется повтором
+        }
+        self.waiters.entry(piece).or_insert_with(Vec::new).push(sender);
+    }
+    fn verified_pieces(&self) -> u32 {
+        self.have.count_ones()
+    }
+    /// Сколько байт осталось скачать
+    fn left(&self) -> u64 {
+        self.have.zeros().map(|i| self.layout.piece_size(i)).sum()
+    }
+    fn stats(&mut self, now: Instant) -> TorrentStats {
+        let pieces = self.layout.pieces_count();
+        let verified_pieces = self.verified_pieces();
+        let left = self.left();
+        let download_rate_average = self.counters.download.average(now);
+        TorrentStats {
+            info_hash: hex::encode(self.meta.info_hash()),
+            pieces,
+            verified_pieces,
+            progress: if pieces == 0 { 1.0 } else { f64::from(verified_pieces) / f64::from(pieces) },
+            size: self.layout.total,
+            left,
+            downloaded: self.counters.downloaded,
+            uploaded: self.counters.uploaded,
+            wasted: self.counters.wasted,
+            download_rate: self.counters.download.rate(now),
+            download_rate_average,
+            upload_rate: self.counters.upload.rate(now),
+            upload_rate_average: self.counters.upload.average(now),
+            eta: stats::eta(left, download_rate_average),
+            peers: PeerCounts {
+                connected: self.connections.len(),
+                known: self.peers.len(),
+                seeds: self.connections.values().filter(|peer| peer.is_seed()).count(),
+            },
+            trackers: self.announcers.iter().map(|announcer| announcer.stats(now)).collect(),
+        }
+    }
+    fn peer_count(&self) -> usize {
+        self.connections.len() + self.connecting.len()
+    }
+    fn add_peer(&mut self, mut peer: Peer, events: &mut EventBus) {
+        if self.verified_pieces() > 0 {
+            peer.send(PeerMessage::Bitfield(self.have.as_bytes().to_vec()));
+        }
+        events.publish(&self.meta.info_hash(), Event::PeerConnected {
+            addr: peer.addr().to_string(),
+            client: peer.client().map(|client| client.to_string()),
+        });
+        self.connections.insert(*peer.addr(), peer);
+    }
+    /// false, если такого пира не было
+    fn remove_peer(&mut self, addr: &SocketAddr, reason: String, events: &mut EventBus) -> bool {
+        if self.connections.remove(addr).is_none() {
+            return false;
+        }
+        for buffer in self.downloads.values_mut() {
+            buffer.release(addr);
+        }
+        events.publish(&self.meta.info_hash(), Event::PeerDisconnected { addr: addr.to_string(), reason });
+        true
+    }
+    fn broadcast(&mut self, message: PeerMessage) {
+        for peer in self.connections.values_mut() {
+            peer.send(message.clone());
+        }
+    }
+    /// Сообщение от пира (кроме Request); Some - собран кусок, его надо проверить
+    fn on_message(&mut self, addr: SocketAddr, message: PeerMessage) -> Option<(u32, Vec<u8>)> {
+        let peer = self.connections.get_mut(&addr)?;
+        peer.on_message(&message);
+        match message {
+            PeerMessage::Choke => for buffer in self.downloads.values_mut() {
+                buffer.release(&addr);
+            },
+            //раздаем всем заинтересованным: число пиров и так ограничено настройками
+            PeerMessage::Interested if peer.state().am_choking => peer.send(PeerMessage::Unchoke),
+            PeerMessage::Piece { block, offset, data } => {
+                let complete = match self.downloads.get_mut(&block) {
+                    Some(buffer) => buffer.add(offset, &data) && buffer.is_complete(),
+                    None => false,
+                };
+                if complete {
+                    return self.downloads.remove(&block).map(|buffer| (block, buffer.into_data()));
+                }
             }
-            err @ Err(_) => err,
+            _ => {}
         }
+        None
+    }
+    /// Интерес к пирам и запросы блоков тех кусков, что ждут стримы. Some - сколько ждать лимита скорости.
+    /// Очередь просматривает только пир со свободным местом в конвейере, и только пока его не заполнит.
+    fn request_blocks(&mut self, limiter: &mut RateLimiter) -> Option<Duration> {
+        let info_hash = self.meta.info_hash();
+        let now = Instant::now();
+        let TorrentConnection {
+            ref wanted, ref scheduled, ref waiters, ref layout, ref mut downloads, ref mut connections, ..
+        } = *self;
+        for peer in connections.values_mut() {
+            let interested = peer.bitfield().intersects(scheduled);
+            if interested != peer.state().am_interested {
+                peer.send(if interested { PeerMessage::Interested } else { PeerMessage::NotInterested });
+            }
+            if !interested || peer.state().peer_choking || peer.requests >= PIPELINE {
+                continue;
+            }
+            for piece in wanted.iter().cloned().filter(|&piece| scheduled.get(piece)) {
+                if !peer.have(piece) {
+                    continue;
+                }
+                let buffer = downloads.entry(piece).or_insert_with(|| PieceBuffer::new(layout.piece_size(piece)));
+                //кусок, который ждет стрим, качаем в обход резерва полосы
+                let priority = if waiters.contains_key(&piece) { Priority::Deadline } else { Priority::Normal };
+                while peer.requests < PIPELINE {
+                    let (offset, length) = match buffer.next_missing() {
+                        Some(block) => block,
+                        None => break,
+                    };
+                    if let Err(wait) = limiter.acquire(&info_hash, Direction::Download, length as usize, priority, now) {
+                        return Some(wait);
+                    }
+                    buffer.requested(offset, *peer.addr());
+                    peer.send(PeerMessage::Request { block: piece, offset, length });
+                    peer.requests += 1;
+                }
+                if peer.requests >= PIPELINE {
+                    break;
+                }
+            }
+        }
+        None
     }
 }
 
+pub struct TorrentClient {
+    info_hash: InfoHash,
+    layout: Rc<Layout>,
+    service: Service,
+}
 
 impl TorrentClient {
-    pub fn new(meta: MetainfoFile) -> Self {
-        unimplemented!();
+    pub fn new(service: Service, meta: MetainfoFile) -> Self {
+        let info_hash = meta.info_hash();
+        let layout = Rc::new(Layout::new(&meta, &service.settings.data_dir));
+        service.send(Command::Add(meta)).ok();
+        TorrentClient { info_hash, layout, service }
+    }
+
+    /// Поток байт из диапазона [offset, offset + length) торрента
+    fn download_range(&self, offset: u64, length: u64) -> SizedStream {
+        if length == 0 {
+            return SizedStream::new(0, futures::stream::empty());
+        }
+        let end = offset + length;
+        let pieces: Vec<u32> = self.layout.range_pieces(offset, length).collect();
+        self.service.send(Command::Schedule {
+            info_hash: self.info_hash,
+            pieces: pieces.clone(),
+        }).ok();
+
+        let info_hash = self.info_hash;
+        let layout = self.layout.clone();
+        let service = self.service.clone();
+        let stream = futures::stream::iter_ok(pieces).and_then(move |piece| {
+            let (sender, receiver) = oneshot::channel();
+            futures::future::result(service.send(Command::Wait { info_hash, piece, sender }))
+                .from_err()
+                .and_then(|_| receiver.from_err())
+                .map(move |_| piece)
+        }).and_then(move |piece| -> Result<Bytes, failure::Error> {
+            let (start, length) = layout.piece_part(piece, offset, end);
+            Ok(layout.read_range(start, length)?)
+        });
+        SizedStream::new(length as usize, stream)
     }
 }
 
 
 impl faces::TorrentClient for TorrentClient {
     fn download(&mut self) -> SizedStream {
+        self.download_range(0, self.layout.total)
+    }
 
-        let stream = futures::stream::once(Ok(Bytes::from("bugoga")));
-        SizedStream::new(0,stream)
+    fn download_part(&mut self, offset: u64, length: u64) -> SizedStream {
+        let offset = std::cmp::min(offset, self.layout.total);
+        let length = std::cmp::min(length, self.layout.total - offset);
+        self.download_range(offset, length)
     }
 
     fn download_file(&mut self, num: usize) -> SizedStream {
+        self.download_file_range(num, 0, u64::max_value())
+    }
 
-        unimplemented!()
+    fn download_file_range(&mut self, num: usize, offset: u64, length: u64) -> SizedStream {
+        match self.layout.file_range(num, offset, length) {
+            Some((offset, length)) => self.download_range(offset, length),
+            None => SizedStream::new(0, futures::stream::once(Err(
+                TorrentError(format!("file {} not found in torrent", num)).into()
+            ))),
+        }
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use actix_web::actix;
-    use actix_web::client;
-    use futures::future::Future;
-
-    #[test]
-    fn test_client() {}
-}
\ No newline at end of file
@@ -0,0 +1,250 @@
+use bip_metainfo::InfoHash;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Меньше блока (16 КиБ) с запасом корзина быть не может, иначе блок никогда не пройдет
+const MIN_BURST: u64 = 64 * 1024;
+/// Доля корзины, которую обычные запросы не трогают: она остается стримам, ждущим куски
+const DEADLINE_RESERVE: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Deadline, //кусок ждет стрим
+    Normal,
+}
+
+/// Байт в секунду, 0 - без ограничений
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimits {
+    pub download_rate: u64,
+    pub upload_rate: u64,
+}
+
+/// Изменение лимитов через API: отсутствующее поле не меняется
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsUpdate {
+    pub download_rate: Option<u64>,
+    pub upload_rate: Option<u64>,
+}
+
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, now: Instant) -> Self {
+        let mut bucket = TokenBucket { rate, tokens: 0.0, last: now };
+        bucket.tokens = bucket.burst();
+        bucket
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = self.tokens.min(self.burst());
+    }
+
+    fn burst(&self) -> f64 {
+        std::cmp::max(self.rate, MIN_BURST) as f64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last {
+            let elapsed = now - self.last;
+            let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            self.tokens = (self.tokens + seconds * self.rate as f64).min(self.burst());
+            self.last = now;
+        }
+    }
+
+    /// Ok - можно передавать сейчас, Err - через сколько попробовать снова
+    pub fn check(&mut self, bytes: usize, priority: Priority, now: Instant) -> Result<(), Duration> {
+        if self.rate == 0 {
+            return Ok(());
+        }
+        self.refill(now);
+        let reserve = match priority {
+            Priority::Deadline => 0.0,
+            Priority::Normal => self.burst() * DEADLINE_RESERVE,
+        };
+        //запрос больше корзины пропускаем, как только она наполнится
+        let bytes = (bytes as f64).min(self.burst() - reserve);
+        let missing = bytes - (self.tokens - reserve);
+        if missing <= 0.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_millis((missing * 1000.0 / self.rate as f64).ceil() as u64))
+        }
+    }
+
+    pub fn take(&mut self, bytes: usize) {
+        if self.rate != 0 {
+            self.tokens = (self.tokens - bytes as f64).max(0.0);
+        }
+    }
+}
+
+struct Buckets {
+    download: TokenBucket,
+    upload: TokenBucket,
+}
+
+impl Buckets {
+    fn new(limits: RateLimits, now: Instant) -> Self {
+        Buckets {
+            download: TokenBucket::new(limits.download_rate, now),
+            upload: TokenBucket::new(limits.upload_rate, now),
+        }
+    }
+    fn get_mut(&mut self, direction: Direction) -> &mut TokenBucket {
+        match direction {
+            Direction::Download => &mut self.download,
+            Direction::Upload => &mut self.upload,
+        }
+    }
+    fn limits(&self) -> RateLimits {
+        RateLimits {
+            download_rate: self.download.rate(),
+            upload_rate: self.upload.rate(),
+        }
+    }
+    fn update(&mut self, update: &LimitsUpdate, now: Instant) {
+        if let Some(rate) = update.download_rate {
+            self.download.set_rate(rate, now);
+        }
+        if let Some(rate) = update.upload_rate {
+            self.upload.set_rate(rate, now);
+        }
+    }
+}
+
+/// Общие лимиты и лимиты отдельных торрентов; передача идет, только если пускают оба
+pub struct RateLimiter {
+    global: Buckets,
+    torrents: HashMap<InfoHash, Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            global: Buckets::new(limits, Instant::now()),
+            torrents: HashMap::new(),
+        }
+    }
+
+    pub fn global(&self) -> RateLimits {
+        self.global.limits()
+    }
+
+    pub fn set_global(&mut self, update: &LimitsUpdate) -> RateLimits {
+        self.global.update(update, Instant::now());
+        self.global.limits()
+    }
+
+    pub fn torrent(&self, info_hash: &InfoHash) -> RateLimits {
+        self.torrents.get(info_hash).map(Buckets::limits).unwrap_or_default()
+    }
+
+    pub fn set_torrent(&mut self, info_hash: InfoHash, update: &LimitsUpdate) -> RateLimits {
+        let now = Instant::now();
+        let buckets = self.torrents.entry(info_hash)
+            .or_insert_with(|| Buckets::new(RateLimits::default(), now));
+        buckets.update(update, now);
+        buckets.limits()
+    }
+
+    pub fn remove_torrent(&mut self, info_hash: &InfoHash) {
+        self.torrents.remove(info_hash);
+    }
+
+    /// Списывает bytes с обеих корзин или возвращает, сколько ждать до следующей попытки
+    pub fn acquire(&mut self, info_hash: &InfoHash, direction: Direction, bytes: usize, priority: Priority, now: Instant)
+        -> Result<(), Duration> {
+        let global = self.global.get_mut(direction).check(bytes, priority, now);
+        let torrent = match self.torrents.get_mut(info_hash) {
+            Some(buckets) => buckets.get_mut(direction).check(bytes, priority, now),
+            None => Ok(()),
+        };
+        match (global, torrent) {
+            (Ok(()), Ok(())) => {}
+            (Err(a), Err(b)) => return Err(std::cmp::max(a, b)),
+            (Err(wait), _) | (_, Err(wait)) => return Err(wait),
+        }
+        self.global.get_mut(direction).take(bytes);
+        if let Some(buckets) = self.torrents.get_mut(info_hash) {
+            buckets.get_mut(direction).take(bytes);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BLOCK: usize = 16 * 1024;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(128 * 1024, start);
+        for _ in 0..8 {
+            assert_eq!(Ok(()), bucket.check(BLOCK, Priority::Deadline, start));
+            bucket.take(BLOCK);
+        }
+        let wait = bucket.check(BLOCK, Priority::Deadline, start).unwrap_err();
+        assert_eq!(Duration::from_millis(125), wait);
+        assert_eq!(Ok(()), bucket.check(BLOCK, Priority::Deadline, start + wait));
+
+        let mut unlimited = TokenBucket::new(0, start);
+        unlimited.take(1 << 30);
+        assert_eq!(Ok(()), unlimited.check(1 << 30, Priority::Normal, start));
+    }
+
+    #[test]
+    fn test_deadline_precedence() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(64 * 1024, start);
+        //обычные запросы выбирают корзину до резерва
+        let mut taken = 0;
+        while bucket.check(BLOCK, Priority::Normal, start).is_ok() {
+            bucket.take(BLOCK);
+            taken += BLOCK;
+        }
+        assert_eq!(48 * 1024, taken);
+        assert_eq!(Ok(()), bucket.check(BLOCK, Priority::Deadline, start));
+    }
+
+    #[test]
+    fn test_limiter() {
+        let start = Instant::now();
+        let hash = InfoHash::from([1u8; 20]);
+        let mut limiter = RateLimiter::new(RateLimits { download_rate: 0, upload_rate: 0 });
+        assert_eq!(
+            RateLimits { download_rate: 64 * 1024, upload_rate: 0 },
+            limiter.set_torrent(hash, &LimitsUpdate { download_rate: Some(64 * 1024), upload_rate: None })
+        );
+        for _ in 0..4 {
+            assert_eq!(Ok(()), limiter.acquire(&hash, Direction::Download, BLOCK, Priority::Deadline, start));
+        }
+        assert!(limiter.acquire(&hash, Direction::Download, BLOCK, Priority::Deadline, start).is_err());
+        assert_eq!(Ok(()), limiter.acquire(&hash, Direction::Upload, BLOCK, Priority::Normal, start));
+        //другой торрент ограничен только общим лимитом
+        let other = InfoHash::from([2u8; 20]);
+        assert_eq!(Ok(()), limiter.acquire(&other, Direction::Download, BLOCK, Priority::Normal, start));
+        assert_eq!(RateLimits::default(), limiter.torrent(&other));
+    }
+}
@@ -1,139 +1,162 @@
 use super::HashString;
 use super::tokio::io;
-use bytes::{Bytes, BytesMut, BufMut, Buf};
+use super::tokio::codec::{Decoder, Encoder};
+use bytes::{Bytes, BytesMut, BufMut};
 
 extern crate byteorder;
-use self::byteorder::{BigEndian, ReadBytesExt};
+use self::byteorder::{BigEndian, ByteOrder};
 use futures::Future;
+use futures::future::Either;
 
 
-type TorrentExtentions = [u8; 8];
+pub const PROTOCOL: &[u8] = b"BitTorrent protocol";
 
 const SIZE_BYTES: usize = 4;
 const PORT_BYTES: usize = 2;
 const HANDSHAKE_DEFAULT_SIZE: usize = 49;
-
-#[derive(Debug, Fail)]
-#[fail(display = "{}", 0)]
-pub struct BitfieldError(String);
-
-pub trait Bitfield: AsRef<[u8]> + Sized {
-    fn empty(count: u32) -> Self;
-    fn full(count: u32) -> Self;
-    fn add_bit(&mut self, index: u32) -> Result<(), BitfieldError>;
-    fn remove_bit(&mut self, index: u32) -> Result<(), BitfieldError>;
-    fn have_bit(&self, index: u32) -> bool;
-    fn interest<T: Bitfield>(&self, rhs: T) -> Result<Self, BitfieldError>;
+const HANDSHAKE_SIZE: usize = HANDSHAKE_DEFAULT_SIZE + 19; //19 == PROTOCOL.len()
+const MAX_MESSAGE_SIZE: usize = 1 << 20; //bitfield на 8 млн кусков или блок в 1 МиБ
+
+/// Возможности, о которых пиры сообщают битами в зарезервированных байтах рукопожатия
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    Dht, //BEP 5
+    Fast, //BEP 6
+    ExtensionProtocol, //BEP 10
 }
 
-impl Bitfield for Vec<u8> {
-    fn empty(blocks_count: u32) -> Self {
-        let mut capacity = blocks_count / 8;
-        if blocks_count % 8 != 0 {
-            capacity += 1;
-        }
-        let mut ret = Vec::with_capacity(capacity as usize);
-        for _ in 0..capacity {
-            ret.push(0u8);
+impl Capability {
+    pub const ALL: [Capability; 3] = [Capability::Dht, Capability::Fast, Capability::ExtensionProtocol];
+
+    fn bit(self) -> (usize, u8) {
+        match self {
+            Capability::Dht => (7, 0x01),
+            Capability::Fast => (7, 0x04),
+            Capability::ExtensionProtocol => (5, 0x10),
         }
-        ret
     }
+}
 
-    fn full(blocks_count: u32) -> Self {
-        let mut overhead = (blocks_count % 8) as u8;
-        if overhead > 0 {
-            overhead = 8 - overhead;
-        }
-        let mut capacity = blocks_count / 8;
-        if overhead > 0 {
-            capacity += 1;
-        }
+/// Зарезервированные байты рукопожатия; неизвестные биты сохраняются как есть
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Extensions([u8; 8]);
 
-        let mut ret = Vec::with_capacity(capacity as usize);
-        for i in 1..capacity { //все, кроме последнего
-            ret.push(0xffu8);
-        }
-        ret.push(0xffu8 << overhead);
-        ret
+impl Extensions {
+    pub fn with(mut self, capability: Capability) -> Self {
+        let (byte, mask) = capability.bit();
+        self.0[byte] |= mask;
+        self
     }
-
-    fn add_bit(&mut self, index: u32) -> Result<(), BitfieldError> {
-        let (byte_index, mask) = calc_byte_index_and_mask(index);
-        let byte = self.get_mut(byte_index).unwrap();
-        *byte = *byte | mask;
-        Ok(())
+    pub fn supports(&self, capability: Capability) -> bool {
+        let (byte, mask) = capability.bit();
+        self.0[byte] & mask != 0
     }
-
-    fn remove_bit(&mut self, index: u32) -> Result<(), BitfieldError> {
-        let (byte_index, mask) = calc_byte_index_and_mask(index);
-        let mask = 0xffu8 ^ mask; //example: 11101111
-        let byte = self.get_mut(byte_index).unwrap();
-        *byte = *byte & mask;
-        Ok(())
+    pub fn capabilities(&self) -> Vec<Capability> {
+        Capability::ALL.iter().cloned().filter(|&c| self.supports(c)).collect()
     }
-
-    fn have_bit(&self, index: u32) -> bool {
-        let (byte_index, mask) = calc_byte_index_and_mask(index);
-        (self.get(byte_index).unwrap_or(&0u8).to_owned() & mask) > 0
+    /// То, что поддерживают обе стороны
+    pub fn common(&self, another: &Extensions) -> Extensions {
+        let mut ret = Extensions::default();
+        for (byte, (a, b)) in ret.0.iter_mut().zip(self.0.iter().zip(another.0.iter())) {
+            *byte = a & b;
+        }
+        ret
     }
+}
 
-    fn interest<T: Bitfield>(&self, rhs: T) -> Result<Self, BitfieldError> {
-        let me: &[u8] = self.as_ref();
-        let another = rhs.as_ref();
-        if me.len() != another.len() {
-            Err(BitfieldError("Different sizes of Bitfields".to_string()))
-        } else {
-            Ok(me.iter()
-                .zip(another.iter())
-                .map(|(&a, &b)| (a & b) ^ b)
-                .collect()
-            )
-        }
+impl AsRef<[u8]> for Extensions {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
     }
 }
 
-fn calc_byte_index_and_mask(index: u32) -> (usize, u8){
-    let offset = (index % 8) as u8;
-    let mask = 1u8 << 7u8 - offset; //example: 00010000
-    ((index / 8) as usize, mask)
+#[derive(Debug, Fail)]
+pub enum HandshakeError {
+    #[fail(display = "{}", _0)]
+    Io(io::Error),
+    #[fail(display = "unsupported protocol {:?}", _0)]
+    Protocol(String),
+    #[fail(display = "peer serves another torrent")]
+    InfoHash,
+    #[fail(display = "connected to ourselves")]
+    SelfConnection,
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Handshake {
-    protocol: String,
-    extentions: TorrentExtentions,
+    extensions: Extensions,
     info_hash: HashString,
     peer_id: HashString,
 }
 
 impl Handshake {
-    pub fn parse<T: io::AsyncRead>(reader: T ) -> impl Future<Item=(Self,T), Error=io::Error> {
-        io::read_exact(reader, [0;1]).and_then(|(reader, buf)|{
-            let protocol_size = buf[0];
-            let mut bytes = BytesMut::with_capacity( HANDSHAKE_DEFAULT_SIZE - 1 + buf[0] as usize);
-            io::read_exact(reader, bytes).map(|(reader,body)|{
-                let mut buf = BytesMut::with_capacity(body.len() +1);
-                buf.put_u8(buf[0]);
-                buf.put(body);
-                //TODO: избавиться от unwrap
-                (parser::parse_handshake(buf.as_ref()).unwrap().1, reader)
-            })
+    pub fn new(info_hash: HashString, peer_id: HashString, extensions: Extensions) -> Self {
+        Handshake { extensions, info_hash, peer_id }
+    }
+    pub fn info_hash(&self) -> &HashString {
+        &self.info_hash
+    }
+    pub fn peer_id(&self) -> &HashString {
+        &self.peer_id
+    }
+    pub fn extensions(&self) -> Extensions {
+        self.extensions
+    }
+    /// Ровно HANDSHAKE_SIZE байт рукопожатия BitTorrent
+    pub fn parse(bytes: &[u8]) -> Result<Self, HandshakeError> {
+        if bytes.len() != HANDSHAKE_SIZE || bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            let size = bytes.first().cloned().unwrap_or(0) as usize;
+            let protocol = bytes.get(1..1 + size).or_else(|| bytes.get(1..)).unwrap_or_default();
+            return Err(HandshakeError::Protocol(String::from_utf8_lossy(protocol).into_owned()));
+        }
+        let mut extensions = Extensions::default();
+        let mut info_hash: HashString = Default::default();
+        let mut peer_id: HashString = Default::default();
+        extensions.0.copy_from_slice(&bytes[20..28]);
+        info_hash.copy_from_slice(&bytes[28..48]);
+        peer_id.copy_from_slice(&bytes[48..]);
+        Ok(Handshake { extensions, info_hash, peer_id })
+    }
+    /// Сначала читаем длину имени протокола: на чужой протокол не ждем оставшиеся байты
+    pub fn read<T: io::AsyncRead>(reader: T) -> impl Future<Item=(Self, T), Error=HandshakeError> {
+        io::read_exact(reader, [0u8; 1]).from_err().and_then(|(reader, size)| {
+            if size[0] as usize != PROTOCOL.len() {
+                let e = HandshakeError::Protocol(format!("{} bytes long protocol name", size[0]));
+                return Either::A(futures::failed(e));
+            }
+            Either::B(io::read_exact(reader, vec![0u8; HANDSHAKE_SIZE - 1]).from_err().and_then(move |(reader, body)| {
+                let mut buf = Vec::with_capacity(HANDSHAKE_SIZE);
+                buf.push(size[0]);
+                buf.extend_from_slice(&body);
+                Handshake::parse(&buf).map(|handshake| (handshake, reader))
+            }))
         })
     }
-    pub fn validate(&self, another: &Handshake) -> bool {
-        self.info_hash.eq(&another.info_hash)
+    /// Проверка ответа на наше рукопожатие
+    pub fn check(&self, response: &Handshake) -> Result<(), HandshakeError> {
+        if response.peer_id == self.peer_id {
+            Err(HandshakeError::SelfConnection)
+        } else if response.info_hash != self.info_hash {
+            Err(HandshakeError::InfoHash)
+        } else {
+            Ok(())
+        }
     }
-
 }
 
 impl Into<Bytes> for Handshake {
     fn into(self) -> Bytes {
-        let protocol = self.protocol.as_bytes();
-        let size = protocol.len();
-        let mut ret = BytesMut::with_capacity(size + HANDSHAKE_DEFAULT_SIZE);
-        ret.put_u8(size as u8);
-        ret.put(protocol);
-        ret.put(self.extentions.as_ref());
+        let mut ret = BytesMut::with_capacity(HANDSHAKE_SIZE);
+        ret.put_u8(PROTOCOL.len() as u8);
+        ret.put(PROTOCOL);
+        ret.put(self.extensions.as_ref());
         ret.put(self.info_hash.as_ref());
         ret.put(self.peer_id.as_ref());
         ret.into()
@@ -172,7 +195,7 @@ fn make_empty_message(message_id: u8) -> Bytes {
     let mut ret = BytesMut::with_capacity(size + SIZE_BYTES);
     let size = size as u32;
     ret.put_u32_be(size);
-    ret.put(message_id);
+    ret.put_u8(message_id);
     ret.into()
 }
 
@@ -181,15 +204,15 @@ impl Into<Bytes> for PeerMessage {
     fn into(self) -> Bytes {
         match self {
             PeerMessage::KeepAlive => Bytes::from([0u8, 0u8, 0u8, 0u8].as_ref()),
-            PeerMessage::Choke => make_empty_message(b'0'),
-            PeerMessage::Unchoke => make_empty_message(b'1'),
-            PeerMessage::Interested => make_empty_message(b'2'),
-            PeerMessage::NotInterested => make_empty_message(b'3'),
+            PeerMessage::Choke => make_empty_message(0),
+            PeerMessage::Unchoke => make_empty_message(1),
+            PeerMessage::Interested => make_empty_message(2),
+            PeerMessage::NotInterested => make_empty_message(3),
             PeerMessage::Have(index) => {
                 let size = 1 + SIZE_BYTES;
                 let mut ret = BytesMut::with_capacity(size + SIZE_BYTES);
                 ret.put_u32_be(size as u32);
-                ret.put(b'4');
+                ret.put_u8(4);
                 ret.put_u32_be(index);
                 ret.into()
             }
@@ -198,7 +221,7 @@ impl Into<Bytes> for PeerMessage {
                 let size = 1 + body.len();
                 let mut ret = BytesMut::with_capacity(size + SIZE_BYTES);
                 ret.put_u32_be(size as u32);
-                ret.put(b'5');
+                ret.put_u8(5);
                 ret.put(body);
                 ret.into()
             }
@@ -206,7 +229,7 @@ impl Into<Bytes> for PeerMessage {
                 let size = 1 + 3 * SIZE_BYTES;
                 let mut ret = BytesMut::with_capacity(size + SIZE_BYTES);
                 ret.put_u32_be(size as u32);
-                ret.put(b'6');
+                ret.put_u8(6);
                 ret.put_u32_be(block);
                 ret.put_u32_be(offset);
                 ret.put_u32_be(length);
@@ -216,7 +239,7 @@ impl Into<Bytes> for PeerMessage {
                 let size = 1 + 2 * SIZE_BYTES + data.len();
                 let mut ret = BytesMut::with_capacity(size + SIZE_BYTES);
                 ret.put_u32_be(size as u32);
-                ret.put(b'7');
+                ret.put_u8(7);
                 ret.put_u32_be(block);
                 ret.put_u32_be(offset);
                 ret.put(data);
@@ -226,7 +249,7 @@ impl Into<Bytes> for PeerMessage {
                 let size = 1 + 3 * SIZE_BYTES;
                 let mut ret = BytesMut::with_capacity(size + SIZE_BYTES);
                 ret.put_u32_be(size as u32);
-                ret.put(b'8');
+                ret.put_u8(8);
                 ret.put_u32_be(block);
                 ret.put_u32_be(offset);
                 ret.put_u32_be(length);
@@ -236,7 +259,7 @@ impl Into<Bytes> for PeerMessage {
                 let size = 1 + PORT_BYTES;
                 let mut ret = BytesMut::with_capacity(size + SIZE_BYTES);
                 ret.put_u32_be(size as u32);
-                ret.put(b'9');
+                ret.put_u8(9);
                 ret.put_u16_be(port);
                 ret.into()
             }
@@ -244,6 +267,80 @@ impl Into<Bytes> for PeerMessage {
     }
 }
 
+/// Кадры протокола: 4 байта длины, id и тело. Сообщения расширений (id > 9) пропускаем.
+#[derive(Default)]
+pub struct PeerCodec;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn valid_size(id: u8, size: usize) -> bool {
+    match id {
+        0..=3 => size == 1,
+        4 => size == 5,
+        5 => size > 1,
+        6 | 8 => size == 13,
+        7 => size >= 9,
+        9 => size == 3,
+        _ => true,
+    }
+}
+
+impl Decoder for PeerCodec {
+    type Item = PeerMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<PeerMessage>, io::Error> {
+        loop {
+            if src.len() < SIZE_BYTES {
+                return Ok(None);
+            }
+            let size = BigEndian::read_u32(&src[..SIZE_BYTES]) as usize;
+            if size > MAX_MESSAGE_SIZE {
+                return Err(invalid(format!("message of {} bytes is too long", size)));
+            }
+            if src.len() < SIZE_BYTES + size {
+                src.reserve(SIZE_BYTES + size - src.len());
+                return Ok(None);
+            }
+            let frame = src.split_to(SIZE_BYTES + size).freeze();
+            if size == 0 {
+                return Ok(Some(PeerMessage::KeepAlive));
+            }
+            let id = frame[SIZE_BYTES];
+            if id > 9 {
+                continue;
+            }
+            if !valid_size(id, size) {
+                return Err(invalid(format!("message {} of {} bytes", id, size)));
+            }
+            if id == 7 {
+                //данные блока не копируем
+                return Ok(Some(PeerMessage::Piece {
+                    block: BigEndian::read_u32(&frame[5..9]),
+                    offset: BigEndian::read_u32(&frame[9..13]),
+                    data: frame.slice_from(13),
+                }));
+            }
+            return parser::parse_message(&frame)
+                .map(|(_, message)| Some(message))
+                .map_err(|_| invalid(format!("malformed message {}", id)));
+        }
+    }
+}
+
+impl Encoder for PeerCodec {
+    type Item = PeerMessage;
+    type Error = io::Error;
+
+    fn encode(&mut self, message: PeerMessage, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let bytes: Bytes = message.into();
+        dst.extend_from_slice(&bytes);
+        Ok(())
+    }
+}
+
 mod parser {
     extern crate nom;
     use super::*;
@@ -251,90 +348,45 @@ mod parser {
         IResult,
         bytes::streaming::*,
         number::streaming::*,
-        character::streaming::anychar,
         sequence::tuple
     };
 
-    fn parse_hash_string(i: &[u8]) -> IResult<&[u8], HashString> {
-        let (i, slice) = take(20usize)(i)?;
-        let mut res: [u8;20] = Default::default();
-        res.copy_from_slice(slice);
-        Ok((i, res))
-    }
-
-    fn parse_torrent_extentions(i: &[u8]) -> IResult<&[u8], TorrentExtentions> {
-        let (i, slice) = take(8usize)(i)?;
-        let mut res: [u8;8] = Default::default();
-        res.copy_from_slice(slice);
-        Ok((i, res))
-    }
-
-    pub fn parse_handshake(i: &[u8]) -> IResult<&[u8], Handshake> {
-        let (i,size) = be_u8(i)?;
-        let (i, (protocol, extentions, info_hash, peer_id)) = tuple((
-            take(size),
-            parse_torrent_extentions,
-            parse_hash_string,
-            parse_hash_string
-        ))(i)?;
-        Ok((i, Handshake {
-            protocol: std::str::from_utf8(protocol).unwrap().to_string(),
-            extentions,
-            info_hash,
-            peer_id
-        }))
-    }
-
-    #[test]
-    fn test_parse_handshake() {
-        let x = Handshake {
-            protocol: "bugoga".to_string(),
-            extentions: [1u8, 2, 3, 4, 5, 6, 7, 8],
-            info_hash: [1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20],
-            peer_id: [20u8, 19, 18, 17, 16, 15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1],
-        };
-        let bytes: Bytes = x.clone().into();
-        let parseRes = parse_handshake(bytes.as_ref());
-        assert_eq!(Result::Ok((b"".as_ref(), x)), parseRes);
-        let (buf, handshake) = parseRes.unwrap();
-    }
-
     pub fn parse_message(i: &[u8]) -> IResult<&[u8],PeerMessage> {
         let (i, size) = be_u32(i)?;
         if size == 0 {
             Ok((i, PeerMessage::KeepAlive))
         } else {
-            let (i, tag) = anychar(i)?;
+            let (i, tag) = be_u8(i)?;
             match tag {
-                '0' => Ok((i, PeerMessage::Choke)),
-                '1' => Ok((i, PeerMessage::Unchoke)),
-                '2' => Ok((i, PeerMessage::Interested)),
-                '3' => Ok((i, PeerMessage::NotInterested)),
-                '4' => {
+                0 => Ok((i, PeerMessage::Choke)),
+                1 => Ok((i, PeerMessage::Unchoke)),
+                2 => Ok((i, PeerMessage::Interested)),
+                3 => Ok((i, PeerMessage::NotInterested)),
+                4 => {
                     let (i, index) = be_u32(i)?;
                     Ok((i, PeerMessage::Have(index)))
                 },
-                '5' => {
+                5 => {
                     let (i, bitfield) = take(size - 1)(i)?;
                     Ok((i, PeerMessage::Bitfield(bitfield.to_vec())))
                 },
-                '6' => {
+                6 => {
                     let (i, (block, offset, length)) = tuple((be_u32, be_u32, be_u32))(i)?;
                     Ok((i, PeerMessage::Request {block, offset, length}))
                 },
-                '7' => {
+                7 => {
                     let (i, (block, offset, data)) = tuple((be_u32, be_u32, take(size -9)))(i)?;
                     Ok((i, PeerMessage::Piece{block, offset, data: data.into()}))
                 },
-                '8' => {
+                8 => {
                     let (i, (block, offset, length)) = tuple((be_u32, be_u32, be_u32))(i)?;
                     Ok((i, PeerMessage::Cancel {block, offset, length}))
                 },
-                '9' => {
+                9 => {
                     let (i, port) = be_u16(i)?;
                     Ok((i, PeerMessage::Port(port)))
                 },
-                _ => unimplemented!()
+                _ => Err(nom::Err::Error((i, nom::error::ErrorKind::Tag))),
             }
         }
     }
@@ -396,56 +448,110 @@ mod test {
     use super::*;
     use bytes::Bytes;
 
+    fn handshake() -> Handshake {
+        Handshake::new([1u8; 20], *b"-MS0001-abcdefghijkl", Extensions::default().with(Capability::Fast))
+    }
+
+    #[test]
+    fn test_handshake() {
+        let bytes: Bytes = handshake().into();
+        assert_eq!(HANDSHAKE_SIZE, bytes.len());
+        assert_eq!(b"\x13BitTorrent protocol\0\0\0\0\0\0\0\x04".as_ref(), &bytes[..28]);
+        assert_eq!(handshake(), Handshake::parse(&bytes).unwrap());
+        let (parsed, _) = Handshake::read(std::io::Cursor::new(bytes.clone())).wait().unwrap();
+        assert_eq!(handshake(), parsed);
+
+        let mut other = bytes.to_vec();
+        other[1..20].copy_from_slice(b"BitTorrent protocoL");
+        match Handshake::parse(&other) {
+            Err(HandshakeError::Protocol(ref name)) if name == "BitTorrent protocoL" => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(Handshake::parse(&bytes[..HANDSHAKE_SIZE - 1]).is_err());
+        match Handshake::read(std::io::Cursor::new(b"\x04HTTP/1.1 200 OK".to_vec())).wait() {
+            Err(HandshakeError::Protocol(_)) => {}
+            other => panic!("unexpected {:?}", other.map(|(h, _)| h)),
+        }
+        match Handshake::read(std::io::Cursor::new(bytes.slice_to(30))).wait() {
+            Err(HandshakeError::Io(_)) => {}
+            other => panic!("unexpected {:?}", other.map(|(h, _)| h)),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let ours = handshake();
+        let mut theirs = Handshake::new([1u8; 20], [7u8; 20], Extensions::default());
+        assert!(ours.check(&theirs).is_ok());
+        theirs.info_hash = [2u8; 20];
+        match ours.check(&theirs) {
+            Err(HandshakeError::InfoHash) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match ours.check(&ours.clone()) {
+            Err(HandshakeError::SelfConnection) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_extensions() {
+        let ours = Extensions::default().with(Capability::Fast).with(Capability::ExtensionProtocol);
+        assert_eq!([0u8, 0, 0, 0, 0, 0x10, 0, 0x04], ours.0);
+        assert_eq!(vec![Capability::Fast, Capability::ExtensionProtocol], ours.capabilities());
+        let theirs = Extensions([0xff, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert_eq!(vec![Capability::Dht, Capability::ExtensionProtocol], theirs.capabilities());
+        assert_eq!(vec![Capability::ExtensionProtocol], ours.common(&theirs).capabilities());
+    }
+
     #[test]
     fn test_empty_messages() {
         let bytes: Bytes = PeerMessage::KeepAlive.into();
         assert_eq!([0u8, 0, 0, 0].as_ref(), bytes.as_ref());
         let bytes: Bytes = PeerMessage::Choke.into();
-        assert_eq!([0u8, 0, 0, 1, b'0'].as_ref(), bytes.as_ref());
+        assert_eq!([0u8, 0, 0, 1, 0].as_ref(), bytes.as_ref());
         let bytes: Bytes = PeerMessage::Unchoke.into();
-        assert_eq!([0u8, 0, 0, 1, b'1'].as_ref(), bytes.as_ref());
+        assert_eq!([0u8, 0, 0, 1, 1].as_ref(), bytes.as_ref());
         let bytes: Bytes = PeerMessage::Interested.into();
-        assert_eq!([0u8, 0, 0, 1, b'2'].as_ref(), bytes.as_ref());
+        assert_eq!([0u8, 0, 0, 1, 2].as_ref(), bytes.as_ref());
         let bytes: Bytes = PeerMessage::NotInterested.into();
-        assert_eq!([0u8, 0, 0, 1, b'3'].as_ref(), bytes.as_ref());
+        assert_eq!([0u8, 0, 0, 1, 3].as_ref(), bytes.as_ref());
     }
 
     #[test]
-    fn test_simple_messages() {
-        let bytes: Bytes = PeerMessage::Have(0x342f21cc).into();
-        assert_eq!([0u8, 0, 0, 5, b'4', 0x34, 0x2f, 0x21, 0xcc].as_ref(), bytes.as_ref());
+    fn test_codec() {
+        let mut codec = PeerCodec;
+        let mut buf = BytesMut::new();
+        for message in vec![
+            PeerMessage::Unchoke,
+            PeerMessage::Piece { block: 3, offset: 16384, data: Bytes::from_static(b"data") },
+            PeerMessage::KeepAlive,
+        ] {
+            codec.encode(message, &mut buf).unwrap();
+        }
+        buf.extend_from_slice(&[0, 0, 0, 3, 20, 0, b'd']); //расширение BEP 10
+        let have: Bytes = PeerMessage::Have(7).into();
+        buf.extend_from_slice(&have[..6]);
+        assert_eq!(Some(PeerMessage::Unchoke), codec.decode(&mut buf).unwrap());
+        assert_eq!(
+            Some(PeerMessage::Piece { block: 3, offset: 16384, data: Bytes::from_static(b"data") }),
+            codec.decode(&mut buf).unwrap()
+        );
+        assert_eq!(Some(PeerMessage::KeepAlive), codec.decode(&mut buf).unwrap());
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+        buf.extend_from_slice(&have[6..]);
+        assert_eq!(Some(PeerMessage::Have(7)), codec.decode(&mut buf).unwrap());
+        assert!(buf.is_empty());
+
+        let mut short_have = BytesMut::from(&[0u8, 0, 0, 3, 4, 0, 0][..]);
+        assert!(codec.decode(&mut short_have).is_err());
+        let mut huge = BytesMut::from(&[0u8, 0x10, 0, 1, 7][..]);
+        assert!(codec.decode(&mut huge).is_err());
     }
 
     #[test]
-    fn test_bit_ops() {
-        let offset = 1 % 8;
-        let mask = 1u8 << 7 - offset;
-        assert_eq!(0b01000000, mask);
-
-        let mask = 0xffu8 ^ mask;
-        assert_eq!(0b10111111, mask);
-
-        let a = 0b11100011u8;
-        let b = 0b00111001u8;
-        assert_eq!(0b00100001u8, a & b);
-        assert_eq!(0b00011000u8, (a & b) ^ b);
+    fn test_simple_messages() {
+        let bytes: Bytes = PeerMessage::Have(0x342f21cc).into();
+        assert_eq!([0u8, 0, 0, 5, 4, 0x34, 0x2f, 0x21, 0xcc].as_ref(), bytes.as_ref());
     }
-
-    #[test]
-    fn test_bitfield() {
-        let bitfield = Vec::full(16);
-        assert_eq!([0b11111111, 0b11111111], bitfield.as_ref());
-        let mut bitfield = Vec::full(19);
-        assert_eq!([0b11111111, 0b11111111, 0b11100000], bitfield.as_ref());
-        bitfield.remove_bit(10); //помним, что нумерация с нуля
-        assert_eq!([255u8, 0b11011111, 0b11100000], bitfield.as_ref());
-        let mut bitfield = Vec::empty(20);
-        assert_eq!([0u8, 0, 0], bitfield.as_ref());
-        bitfield.add_bit(15);
-        assert_eq!([0u8, 1, 0], bitfield.as_ref());
-
-        let a = vec![0b00000000u8, 0b00011100, 0b11100011];
-        let b = vec![0b11100011u8, 0b00011100, 0b00111001];
-        assert_eq!(vec![0b11100011u8, 0b00000000, 0b00011000], a.interest(b).unwrap())
-    }
-}
\ No newline at end of file
+}
@@ -5,10 +5,89 @@ extern crate tokio;
 mod faces;
 mod implement;
 mod tracker;
+mod announce;
 mod message;
 mod peer;
+mod blocks;
+mod bitfield;
+mod files;
+mod resume;
+mod create;
+mod limit;
+mod stats;
+mod events;
+mod peer_id;
 pub use self::faces::*;
+pub use self::implement::{Service, new_service};
+pub use self::create::{create, CreateOptions};
+pub use self::peer::PeerError;
+pub use self::tracker::AnnounceResponseError;
+pub use self::message::{HandshakeError, Capability};
+pub use self::bitfield::BitfieldError;
+pub use self::limit::{RateLimits, LimitsUpdate};
+pub use self::stats::{TorrentStats, PeerInfo};
+pub use self::events::{Event, KeepAlive};
 
-pub fn new_client(meta: bip_metainfo::MetainfoFile) -> impl TorrentClient {
-    implement::TorrentClient::new(meta)
-}
\ No newline at end of file
+use futures::Future;
+use futures::sync::oneshot;
+
+pub fn add(service: &Service, meta: bip_metainfo::MetainfoFile) {
+    service.send(implement::Command::Add(meta)).ok();
+}
+
+pub fn new_client(service: &Service, meta: bip_metainfo::MetainfoFile) -> impl TorrentClient {
+    implement::TorrentClient::new(service.clone(), meta)
+}
+
+/// Текущие лимиты скорости (общие или торрента), с изменением, если передан update
+pub fn limits(service: &Service, info_hash: Option<bip_metainfo::InfoHash>, update: Option<LimitsUpdate>)
+    -> impl Future<Item=RateLimits, Error=failure::Error> {
+    let (sender, receiver) = oneshot::channel();
+    futures::future::result(service.send(implement::Command::Limits { info_hash, update, sender }))
+        .from_err()
+        .and_then(|_| receiver.from_err())
+}
+
+pub fn stats(service: &Service, info_hash: bip_metainfo::InfoHash)
+    -> impl Future<Item=Option<TorrentStats>, Error=failure::Error> {
+    let (sender, receiver) = oneshot::channel();
+    futures::future::result(service.send(implement::Command::Stats { info_hash, sender }))
+        .from_err()
+        .and_then(|_| receiver.from_err())
+}
+
+/// Подключенные пиры торрента; None, если торрент не запущен
+pub fn peers(service: &Service, info_hash: bip_metainfo::InfoHash)
+    -> impl Future<Item=Option<Vec<PeerInfo>>, Error=failure::Error> {
+    let (sender, receiver) = oneshot::channel();
+    futures::future::result(service.send(implement::Command::Peers { info_hash, sender }))
+        .from_err()
+        .and_then(|_| receiver.from_err())
+}
+
+/// Подписка на события торрента; None, если торрент не запущен
+pub fn subscribe(service: &Service, info_hash: bip_metainfo::InfoHash)
+    -> impl Future<Item=Option<futures::sync::mpsc::UnboundedReceiver<Event>>, Error=failure::Error> {
+    let (sender, receiver) = oneshot::channel();
+    futures::future::result(service.send(implement::Command::Subscribe { info_hash, sender }))
+        .from_err()
+        .and_then(|_| receiver.from_err())
+}
+
+/// Остановка торрента и удаление его служебных файлов (и данных, если delete_data)
+pub fn remove(service: &Service, meta: bip_metainfo::MetainfoFile, delete_data: bool)
+    -> impl Future<Item=(), Error=failure::Error> {
+    let (sender, receiver) = oneshot::channel();
+    futures::future::result(service.send(implement::Command::Remove { meta, delete_data, sender }))
+        .from_err()
+        .and_then(|_| receiver.from_err())
+        .and_then(|result| result.map_err(failure::Error::from))
+}
+
+/// Сообщает трекерам stopped по всем торрентам; завершается, когда они ответили или вышло время
+pub fn shutdown(service: &Service) -> impl Future<Item=(), Error=failure::Error> {
+    let (sender, receiver) = oneshot::channel();
+    futures::future::result(service.send(implement::Command::Shutdown { sender }))
+        .from_err()
+        .and_then(|_| receiver.from_err())
+}
@@ -1,10 +1,19 @@
 use super::tokio::net::TcpStream;
 use super::tokio::io;
+use super::tokio::codec::Framed;
+use super::tokio::timer::{timeout, Interval, Timeout};
 use failure::Fail;
-use torrent::message::{PeerMessage, Handshake, Bitfield};
+use torrent::message::{PeerMessage, PeerCodec, Handshake, HandshakeError, Extensions};
+use torrent::bitfield::Bitfield;
 use bytes::{Bytes};
-use futures::{Future};
+use futures::{Future, Stream, Sink, Async, AsyncSink, Poll};
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use std::collections::{HashSet, VecDeque};
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use super::{HashString, Settings};
+use super::peer_id::{self, Client};
+use super::stats::PeerInfo;
 
 use super::tokio::io::Error;
 use torrent::peer::PeerError::IoError;
@@ -12,57 +21,520 @@ use torrent::peer::PeerError::IoError;
 
 #[derive(Debug,Fail)]
 pub enum PeerError {
-    #[fail(display="{}",0)]
+    #[fail(display="{}",_0)]
     IoError(io::Error),
-    #[fail(display="{}",0)]
+    #[fail(display="{}",_0)]
     Simple(String),
-    #[fail(display="Handshake error")]
-    Handshake,
+    #[fail(display="handshake error: {}", _0)]
+    Handshake(HandshakeError),
+    #[fail(display="{} timed out", _0)]
+    Timeout(&'static str),
 }
 impl From<io::Error> for PeerError {
     fn from(e: Error) -> Self {
         IoError(e)
     }
 }
+impl From<HandshakeError> for PeerError {
+    fn from(e: HandshakeError) -> Self {
+        PeerError::Handshake(e)
+    }
+}
+
+fn timeout_error<E: Into<PeerError>>(e: timeout::Error<E>, stage: &'static str) -> PeerError {
+    if e.is_elapsed() {
+        return PeerError::Timeout(stage);
+    }
+    match e.into_inner() {
+        Some(e) => e.into(),
+        None => PeerError::Simple("timer failure".to_string()),
+    }
+}
+
+/// Отправляем свое рукопожатие и проверяем ответное: тот же торрент и не мы сами
+pub fn handshake<T>(stream: T, ours: Handshake) -> impl Future<Item=(Handshake, T), Error=HandshakeError>
+    where T: io::AsyncRead + io::AsyncWrite {
+    let bytes: Bytes = ours.clone().into();
+    io::write_all(stream, bytes).from_err()
+        .and_then(|(stream, _)| Handshake::read(stream))
+        .and_then(move |(theirs, stream)| ours.check(&theirs).map(|_| (theirs, stream)))
+}
+
+/// Соединение и обмен рукопожатиями, у каждого шага свой таймаут
+pub fn connect(addr: SocketAddr, handshake: Handshake, settings: &Settings)
+    -> impl Future<Item=(Handshake, TcpStream), Error=PeerError> {
+    let handshake_timeout = Duration::from_secs(settings.handshake_timeout);
+    Timeout::new(TcpStream::connect(&addr), Duration::from_secs(settings.connect_timeout))
+        .map_err(|e| timeout_error(e, "connect"))
+        .and_then(move |stream| {
+            Timeout::new(self::handshake(stream, handshake), handshake_timeout)
+                .map_err(|e| timeout_error(e, "handshake"))
+        })
+}
+
+/// Состояние по BEP 3: обе стороны начинают зажатыми и незаинтересованными
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct State {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State { am_choking: true, am_interested: false, peer_choking: true, peer_interested: false }
+    }
+}
+
+impl State {
+    fn on_send(&mut self, message: &PeerMessage) {
+        match message {
+            PeerMessage::Choke => self.am_choking = true,
+            PeerMessage::Unchoke => self.am_choking = false,
+            PeerMessage::Interested => self.am_interested = true,
+            PeerMessage::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+    }
+    fn on_receive(&mut self, message: &PeerMessage) {
+        match message {
+            PeerMessage::Choke => self.peer_choking = true,
+            PeerMessage::Unchoke => self.peer_choking = false,
+            PeerMessage::Interested => self.peer_interested = true,
+            PeerMessage::NotInterested => self.peer_interested = false,
+            _ => {}
+        }
+    }
+}
+
+/// Что сессия сообщает торренту
+#[derive(Debug)]
+pub enum PeerEvent {
+    Message(PeerMessage),
+    Disconnected(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub keep_alive: Duration, //молчим дольше - шлем KeepAlive
+    pub idle: Duration, //пир молчит дольше - отключаемся
+    pub stall: Duration, //на запросы нет блоков дольше - отключаемся
+    pub tick: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            keep_alive: Duration::from_secs(120),
+            idle: Duration::from_secs(180),
+            stall: Duration::from_secs(60),
+            tick: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Актор одного соединения: пишет команды торрента в сокет, разбирает входящие сообщения,
+/// следит за таймаутами. Завершается событием Disconnected.
+pub struct Session<T> {
+    framed: Framed<T, PeerCodec>,
+    commands: UnboundedReceiver<PeerMessage>,
+    events: UnboundedSender<PeerEvent>,
+    outbox: VecDeque<PeerMessage>,
+    state: State,
+    pieces: u32,
+    requests: HashSet<(u32, u32)>, //(кусок, смещение) без ответа
+    timeouts: Timeouts,
+    ticker: Interval,
+    last_received: Instant,
+    last_sent: Instant,
+    last_block: Instant,
+}
+
+impl<T: io::AsyncRead + io::AsyncWrite> Session<T> {
+    pub fn new(stream: T, pieces: u32, commands: UnboundedReceiver<PeerMessage>,
+               events: UnboundedSender<PeerEvent>, timeouts: Timeouts) -> Self {
+        let now = Instant::now();
+        Session {
+            framed: Framed::new(stream, PeerCodec),
+            commands,
+            events,
+            outbox: VecDeque::new(),
+            state: State::default(),
+            pieces,
+            requests: HashSet::new(),
+            timeouts,
+            ticker: Interval::new(now + timeouts.tick, timeouts.tick),
+            last_received: now,
+            last_sent: now,
+            last_block: now,
+        }
+    }
+
+    fn on_send(&mut self, message: &PeerMessage) {
+        self.state.on_send(message);
+        match *message {
+            PeerMessage::Request { block, offset, .. } => {
+                if self.requests.is_empty() {
+                    self.last_block = Instant::now();
+                }
+                self.requests.insert((block, offset));
+            }
+            PeerMessage::Cancel { block, offset, .. } => {
+                self.requests.remove(&(block, offset));
+            }
+            _ => {}
+        }
+    }
+
+    /// Err - причина разрыва
+    fn on_receive(&mut self, message: PeerMessage) -> Result<(), String> {
+        self.state.on_receive(&message);
+        match message {
+            PeerMessage::KeepAlive | PeerMessage::Port(_) => return Ok(()),
+            //BEP 3: зажав нас, пир отбрасывает наши запросы
+            PeerMessage::Choke => self.requests.clear(),
+            PeerMessage::Have(index) if index >= self.pieces => {
+                return Err(format!("have for piece {} of {}", index, self.pieces));
+            }
+            PeerMessage::Bitfield(ref bytes) => {
+                Bitfield::from_bytes(bytes, self.pieces).map_err(|e| e.to_string())?;
+            }
+            PeerMessage::Request { .. } if self.state.am_choking => return Ok(()),
+            PeerMessage::Piece { block, offset, .. } => {
+                if !self.requests.remove(&(block, offset)) {
+                    return Ok(()); //не запрашивали или запрос уже отменен
+                }
+                self.last_block = Instant::now();
+            }
+            _ => {}
+        }
+        self.events.unbounded_send(PeerEvent::Message(message)).map_err(|_| "torrent is gone".to_string())
+    }
+
+    /// Ready - сессия закончена, с причиной
+    fn poll_session(&mut self) -> Poll<String, PeerError> {
+        loop {
+            match self.commands.poll() {
+                Ok(Async::Ready(Some(message))) => {
+                    self.on_send(&message);
+                    self.outbox.push_back(message);
+                }
+                Ok(Async::Ready(None)) | Err(_) => return Ok(Async::Ready("closed".to_string())),
+                Ok(Async::NotReady) => break,
+            }
+        }
+        loop {
+            match self.framed.poll()? {
+                Async::Ready(Some(message)) => {
+                    self.last_received = Instant::now();
+                    if let Err(reason) = self.on_receive(message) {
+                        return Ok(Async::Ready(reason));
+                    }
+                }
+                Async::Ready(None) => return Ok(Async::Ready("connection closed by peer".to_string())),
+                Async::NotReady => break,
+            }
+        }
+        while let Async::Ready(Some(now)) = self.ticker.poll().map_err(|e| PeerError::Simple(e.to_string()))? {
+            if now - self.last_received > self.timeouts.idle {
+                return Ok(Async::Ready("idle".to_string()));
+            }
+            if !self.requests.is_empty() && now - self.last_block > self.timeouts.stall {
+                return Ok(Async::Ready("stalled".to_string()));
+            }
+            if now - self.last_sent >= self.timeouts.keep_alive && self.outbox.is_empty() {
+                self.outbox.push_back(PeerMessage::KeepAlive);
+            }
+        }
+        while let Some(message) = self.outbox.pop_front() {
+            match self.framed.start_send(message)? {
+                AsyncSink::Ready => self.last_sent = Instant::now(),
+                AsyncSink::NotReady(message) => {
+                    self.outbox.push_front(message);
+                    break;
+                }
+            }
+        }
+        self.framed.poll_complete()?;
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T: io::AsyncRead + io::AsyncWrite> Future for Session<T> {
+    type Item = ();
+    type Error = ();
 
-enum PeerState {
-    Chocked,
-    Unchocked,
+    fn poll(&mut self) -> Poll<(), ()> {
+        let reason = match self.poll_session() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(reason)) => reason,
+            Err(e) => e.to_string(),
+        };
+        self.events.unbounded_send(PeerEvent::Disconnected(reason)).ok();
+        Ok(Async::Ready(()))
+    }
 }
 
+/// Пир глазами торрента: копия состояния сессии и канал команд к ней
 pub struct Peer {
-    channel: TcpStream,
-    bitfield: Vec<u8>,
-    state: (PeerState, PeerState),
+    addr: SocketAddr,
+    id: HashString,
+    extensions: Extensions, //общие с пиром возможности
+    bitfield: Bitfield,
+    state: State,
+    pub requests: usize, //запрошено блоков без ответа
+    sender: UnboundedSender<PeerMessage>,
 }
 
 impl Peer {
-    pub fn new(addr: SocketAddr, handshake: Handshake) -> impl Future<Item=Self,Error=PeerError> {
-        let handshake_request = handshake.clone();
-        TcpStream::connect(&addr).and_then( |stream| {
-            let bytes: Bytes = handshake.into();
-            io::write_all(stream, bytes)
-        }).and_then(|(stream, _)| {
-            Handshake::parse(stream)
-        }).from_err().and_then(move |(handshake_response, stream)| {
-            if handshake_request.validate(&handshake_response) {
-                futures::future::ok(stream)
-            } else {
-                futures::future::err(PeerError::Handshake)
+    pub fn new(addr: SocketAddr, theirs: &Handshake, ours: Extensions, pieces: u32,
+               sender: UnboundedSender<PeerMessage>) -> Self {
+        Peer {
+            addr,
+            id: *theirs.peer_id(),
+            extensions: ours.common(&theirs.extensions()),
+            bitfield: Bitfield::new(pieces),
+            state: State::default(),
+            requests: 0,
+            sender,
+        }
+    }
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+    pub fn id(&self) -> &HashString {
+        &self.id
+    }
+    pub fn extensions(&self) -> Extensions {
+        self.extensions
+    }
+    pub fn state(&self) -> State {
+        self.state
+    }
+    pub fn client(&self) -> Option<Client> {
+        peer_id::client(&self.id)
+    }
+    pub fn info(&self) -> PeerInfo {
+        PeerInfo {
+            addr: self.addr.to_string(),
+            id: self.id.iter().flat_map(|&c| std::ascii::escape_default(c)).map(char::from).collect(),
+            client: self.client(),
+            capabilities: self.extensions.capabilities(),
+            seed: self.is_seed(),
+            state: self.state,
+        }
+    }
+    pub fn send(&mut self, message: PeerMessage) {
+        self.state.on_send(&message);
+        self.sender.unbounded_send(message).ok();
+    }
+    /// Сообщение уже проверено сессией
+    pub fn on_message(&mut self, message: &PeerMessage) {
+        self.state.on_receive(message);
+        match message {
+            PeerMessage::Choke => self.requests = 0,
+            PeerMessage::Have(index) => {
+                self.bitfield.set(*index).ok();
             }
-        }).and_then( |stream| {
-            let bytes: Bytes = PeerMessage::Interested.into();
-            io::write_all(stream, bytes).from_err()
-        }).and_then(|(stream, _)| {
-            Ok(Peer {
-                channel: stream,
-                bitfield: vec![],
-                state: (PeerState::Unchocked, PeerState::Chocked)
-            })
-        })
+            PeerMessage::Bitfield(bytes) => {
+                if let Ok(bitfield) = Bitfield::from_bytes(bytes, self.bitfield.len()) {
+                    self.bitfield = bitfield;
+                }
+            }
+            PeerMessage::Piece { .. } => self.requests = self.requests.saturating_sub(1),
+            _ => {}
+        }
+    }
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
     }
     pub fn have(&self, piece: u32) -> bool {
-        self.bitfield.have_bit(piece)
+        self.bitfield.get(piece)
+    }
+    pub fn is_seed(&self) -> bool {
+        self.bitfield.is_full()
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::tokio::runtime::current_thread::Runtime;
+    use futures::sync::mpsc;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn settings() -> Settings {
+        Settings { connect_timeout: 1, handshake_timeout: 1, ..Settings::default() }
+    }
+
+    /// Пир на localhost: получает наше рукопожатие и делает с сокетом, что скажут
+    fn serve<F: FnOnce(Vec<u8>, std::net::TcpStream) + Send + 'static>(peer: F) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0u8; 68];
+            stream.read_exact(&mut request).unwrap();
+            peer(request, stream);
+        });
+        addr
+    }
+
+    fn reply(bytes: Vec<u8>) -> impl FnOnce(Vec<u8>, std::net::TcpStream) + Send + 'static {
+        move |_, mut stream| {
+            stream.write_all(&bytes).unwrap();
+            std::thread::sleep(Duration::from_secs(2));
+        }
+    }
+
+    fn ours() -> Handshake {
+        Handshake::new([1u8; 20], *b"-MS0001-000000000000", Extensions::default())
+    }
+
+    fn theirs() -> Vec<u8> {
+        let bytes: Bytes = Handshake::new([1u8; 20], *b"-qB4250-000000000000", Extensions::default()).into();
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn test_connect() {
+        let addr = serve(|request, stream| {
+            assert_eq!(&request[..48], &theirs()[..48]);
+            reply(theirs())(request, stream)
+        });
+        let (handshake, _) = Runtime::new().unwrap().block_on(connect(addr, ours(), &settings())).unwrap();
+        let (sender, _) = mpsc::unbounded();
+        let peer = Peer::new(addr, &handshake, Extensions::default(), 8, sender);
+        assert_eq!(b"-qB4250-000000000000", peer.id());
+        assert_eq!("qBittorrent 4.2.5", peer.client().unwrap().to_string());
+    }
+
+    #[test]
+    fn test_self_connection() {
+        //пир возвращает наше же рукопожатие
+        let addr = serve(|request, stream| reply(request.clone())(request, stream));
+        match Runtime::new().unwrap().block_on(connect(addr, ours(), &settings())) {
+            Err(PeerError::Handshake(HandshakeError::SelfConnection)) => {}
+            other => panic!("unexpected {:?}", other.map(|(handshake, _)| handshake)),
+        }
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        match Runtime::new().unwrap().block_on(connect(serve(reply(Vec::new())), ours(), &settings())) {
+            Err(PeerError::Timeout("handshake")) => {}
+            other => panic!("unexpected {:?}", other.map(|(handshake, _)| handshake)),
+        }
+    }
+
+    fn timeouts() -> Timeouts {
+        Timeouts {
+            keep_alive: Duration::from_millis(100),
+            idle: Duration::from_millis(500),
+            stall: Duration::from_millis(300),
+            tick: Duration::from_millis(20),
+        }
+    }
+
+    /// Сессия с пиром peer; commands уходят в сессию сразу. Возвращает все события сессии.
+    fn session<F>(peer: F, commands: Vec<PeerMessage>) -> Vec<PeerEvent>
+        where F: FnOnce(Vec<u8>, std::net::TcpStream) + Send + 'static {
+        let addr = serve(move |request, mut stream| {
+            stream.write_all(&theirs()).unwrap();
+            peer(request, stream)
+        });
+        let mut runtime = Runtime::new().unwrap();
+        let (_, stream) = runtime.block_on(connect(addr, ours(), &settings())).unwrap();
+        let (sender, receiver) = mpsc::unbounded();
+        let (events, received) = mpsc::unbounded();
+        for command in commands {
+            sender.unbounded_send(command).unwrap();
+        }
+        runtime.block_on(Session::new(stream, 16, receiver, events, timeouts())).unwrap();
+        drop(sender);
+        received.collect().wait().unwrap()
+    }
+
+    fn message(message: PeerMessage) -> Vec<u8> {
+        let bytes: Bytes = message.into();
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn test_session_messages() {
+        let events = session(|_, mut stream| {
+            let mut buf = Vec::new();
+            for m in vec![
+                PeerMessage::Bitfield(vec![0b1000_0000, 0]),
+                PeerMessage::Unchoke,
+                PeerMessage::Have(3),
+                PeerMessage::Piece { block: 1, offset: 0, data: Bytes::from_static(b"unrequested") },
+                PeerMessage::Have(16),
+            ] {
+                buf.extend(message(m));
+            }
+            stream.write_all(&buf).unwrap();
+            std::thread::sleep(Duration::from_secs(1));
+        }, Vec::new());
+        let events: Vec<String> = events.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(vec![
+            "Message(Bitfield([128, 0]))",
+            "Message(Unchoke)",
+            "Message(Have(3))",
+            "Disconnected(\"have for piece 16 of 16\")",
+        ], events);
+    }
+
+    #[test]
+    fn test_session_keep_alive_and_idle() {
+        let events = session(|_, mut stream| {
+            //ждем Interested, потом KeepAlive, и молчим
+            let mut buf = [0u8; 9];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!([0u8, 0, 0, 1, 2, 0, 0, 0, 0], buf);
+            std::thread::sleep(Duration::from_secs(1));
+        }, vec![PeerMessage::Interested]);
+        match events.last() {
+            Some(PeerEvent::Disconnected(reason)) => assert_eq!("idle", reason),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_session_stall() {
+        let events = session(|_, mut stream| {
+            //отвечаем KeepAlive, чтобы не отключили по простою, но блок не шлем
+            for _ in 0..10 {
+                if stream.write_all(&[0, 0, 0, 0]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }, vec![PeerMessage::Request { block: 0, offset: 0, length: 16384 }]);
+        match events.last() {
+            Some(PeerEvent::Disconnected(reason)) => assert_eq!("stalled", reason),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_state() {
+        let (sender, receiver) = mpsc::unbounded();
+        let handshake = Handshake::new([1u8; 20], [2u8; 20], Extensions::default());
+        let mut peer = Peer::new("127.0.0.1:1".parse().unwrap(), &handshake, Extensions::default(), 10, sender);
+        assert_eq!(State::default(), peer.state());
+        peer.send(PeerMessage::Interested);
+        peer.on_message(&PeerMessage::Unchoke);
+        peer.on_message(&PeerMessage::Have(9));
+        assert!(peer.state().am_interested && !peer.state().peer_choking);
+        assert!(peer.have(9) && !peer.have(8));
+        peer.on_message(&PeerMessage::Bitfield(vec![0xff, 0b1110_0000]));
+        assert!(!peer.have(10));
+        peer.on_message(&PeerMessage::Bitfield(vec![0xff, 0b1100_0000]));
+        assert!(peer.is_seed());
+        drop(peer);
+        assert_eq!(vec![PeerMessage::Interested], receiver.collect().wait().unwrap());
+    }
+}
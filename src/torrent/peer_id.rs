@@ -0,0 +1,169 @@
+//! Наш peer_id в стиле Azureus и распознавание клиента по чужим peer_id
+
+use super::HashString;
+use std::fmt;
+
+/// -MS0001-: media-service 0.0.0.1
+pub const PREFIX: &[u8; 8] = b"-MS0001-";
+
+const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Новый peer_id на каждый запуск: префикс и 12 случайных печатных символов
+pub fn generate() -> HashString {
+    let mut id: HashString = Default::default();
+    id[..PREFIX.len()].copy_from_slice(PREFIX);
+    let random = uuid::Uuid::new_v4();
+    for (byte, random) in id[PREFIX.len()..].iter_mut().zip(random.as_bytes().iter()) {
+        *byte = ALPHABET[*random as usize % ALPHABET.len()];
+    }
+    id
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Client {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.version {
+            Some(ref version) => write!(f, "{} {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+fn azureus_name(code: &[u8]) -> Option<&'static str> {
+    Some(match code {
+        b"AG" => "Ares",
+        b"AZ" => "Vuze",
+        b"BC" => "BitComet",
+        b"BI" => "BiglyBT",
+        b"BL" => "BitLord",
+        b"BT" => "BitTorrent",
+        b"DE" => "Deluge",
+        b"FD" => "Free Download Manager",
+        b"FG" => "FlashGet",
+        b"FW" => "FrostWire",
+        b"KT" => "KTorrent",
+        b"LT" => "libtorrent",
+        b"lt" => "libTorrent (rakshasa)",
+        b"LW" => "LimeWire",
+        b"MS" => "media-service",
+        b"PI" => "PicoTorrent",
+        b"qB" => "qBittorrent",
+        b"SD" => "Thunder",
+        b"TL" => "Tribler",
+        b"TR" => "Transmission",
+        b"UM" => "µTorrent Mac",
+        b"UT" => "µTorrent",
+        b"UW" => "µTorrent Web",
+        b"WD" => "WebTorrent Desktop",
+        b"WW" => "WebTorrent",
+        b"XL" => "Xunlei",
+        _ => return None,
+    })
+}
+
+/// Цифра версии: 0-9, дальше A=10 .. Z=35, a=36 .. z=61
+fn version_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'Z' => Some(c - b'A' + 10),
+        b'a'..=b'z' => Some(c - b'a' + 36),
+        _ => None,
+    }
+}
+
+fn azureus_version(code: &[u8], version: &[u8]) -> Option<String> {
+    if code == b"TR" {
+        //Transmission: 2940 - 2.94, 300Z - 3.00 в разработке
+        let digits = std::str::from_utf8(&version[..3]).ok().filter(|d| d.bytes().all(|c| c.is_ascii_digit()))?;
+        let suffix = if version[3] == b'Z' || version[3] == b'X' { "+" } else { "" };
+        return Some(format!("{}.{}{}", &digits[..1], &digits[1..], suffix));
+    }
+    let mut digits = version.iter().map(|&c| version_digit(c)).collect::<Option<Vec<u8>>>()?;
+    while digits.len() > 2 && digits.last() == Some(&0) {
+        digits.pop();
+    }
+    Some(digits.iter().map(ToString::to_string).collect::<Vec<_>>().join("."))
+}
+
+fn shadow_name(code: u8) -> Option<&'static str> {
+    Some(match code {
+        b'A' => "ABC",
+        b'O' => "Osprey Permaseed",
+        b'Q' => "BTQueue",
+        b'R' => "Tribler",
+        b'S' => "Shadow's client",
+        b'T' => "BitTornado",
+        b'U' => "UPnP NAT Bit Torrent",
+        _ => return None,
+    })
+}
+
+/// Клиент по peer_id: стиль Azureus (-qB4250-), Mainline (M7-10-3-) и Shadow (T03I-----)
+pub fn client(id: &HashString) -> Option<Client> {
+    if id[0] == b'-' && id[7] == b'-' {
+        let name = azureus_name(&id[1..3])?;
+        return Some(Client { name: name.to_string(), version: azureus_version(&id[1..3], &id[3..7]) });
+    }
+    if id[0] == b'M' && id[1].is_ascii_digit() {
+        let end = (1..id.len() - 1).find(|&i| id[i] == b'-' && id[i + 1] == b'-')?;
+        let version = std::str::from_utf8(&id[1..end]).ok()?;
+        if version.split('-').all(|part| !part.is_empty() && part.bytes().all(|c| c.is_ascii_digit())) {
+            return Some(Client { name: "Mainline".to_string(), version: Some(version.replace('-', ".")) });
+        }
+        return None;
+    }
+    let name = shadow_name(id[0])?;
+    let digits = id[1..6].iter()
+        .take_while(|&&c| c != b'-')
+        .map(|&c| if c == b'.' { Some(0) } else { version_digit(c) })
+        .collect::<Option<Vec<u8>>>()?;
+    if digits.is_empty() {
+        return None;
+    }
+    let version = digits.iter().map(ToString::to_string).collect::<Vec<_>>().join(".");
+    Some(Client { name: name.to_string(), version: Some(version) })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn id(prefix: &[u8]) -> HashString {
+        let mut id = *b"xxxxxxxxxxxxxxxxxxxx";
+        id[..prefix.len()].copy_from_slice(prefix);
+        id
+    }
+
+    fn describe(prefix: &[u8]) -> Option<String> {
+        client(&id(prefix)).map(|client| client.to_string())
+    }
+
+    #[test]
+    fn test_generate() {
+        let (a, b) = (generate(), generate());
+        assert_eq!(PREFIX, &a[..8]);
+        assert!(a[8..].iter().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(a, b);
+        assert_eq!(Some("media-service 0.0.0.1".to_string()), describe(&a));
+    }
+
+    #[test]
+    fn test_client() {
+        assert_eq!(Some("qBittorrent 4.2.5".to_string()), describe(b"-qB4250-"));
+        assert_eq!(Some("Transmission 2.94".to_string()), describe(b"-TR2940-"));
+        assert_eq!(Some("Transmission 3.00+".to_string()), describe(b"-TR300Z-"));
+        assert_eq!(Some("libtorrent 1.2".to_string()), describe(b"-LT1200-"));
+        assert_eq!(Some("µTorrent 3.5.5".to_string()), describe(b"-UT3550-"));
+        assert_eq!(Some("Deluge 1.3.12".to_string()), describe(b"-DE13C0-"));
+        assert_eq!(Some("Mainline 7.10.3".to_string()), describe(b"M7-10-3--"));
+        assert_eq!(Some("BitTornado 0.3.18".to_string()), describe(b"T03I-----"));
+        assert_eq!(None, describe(b"-ZZ1000-"));
+        assert_eq!(Some("qBittorrent".to_string()), describe(b"-qB4?50-"));
+        assert_eq!(None, describe(b"\x00\x00\x00\x00"));
+    }
+}
@@ -0,0 +1,141 @@
+use failure::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use super::files::Layout;
+use super::TorrentError;
+use super::bitfield::Bitfield;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct FileStamp {
+    length: u64,
+    mtime: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ResumeData {
+    bitfield: String,
+    files: Vec<Option<FileStamp>>, //None - файла еще нет: они создаются по первому записанному куску
+    #[serde(default)]
+    completed: bool,
+}
+
+/// Что известно о торренте с прошлого запуска
+#[derive(Debug, PartialEq)]
+pub struct Resume {
+    pub have: Bitfield,
+    pub completed: bool, //докачан, но completed трекерам еще не отправлен
+}
+
+pub fn resume_path(root: &Path, info_hash: &str) -> PathBuf {
+    root.join(format!("{}.resume", info_hash))
+}
+
+fn file_stamps(layout: &Layout) -> Option<Vec<Option<FileStamp>>> {
+    layout.files.iter().map(|f| {
+        let meta = match fs::metadata(&f.path) {
+            Ok(meta) => meta,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Some(None),
+            Err(_) => return None,
+        };
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        Some(Some(FileStamp { length: meta.len(), mtime }))
+    }).collect()
+}
+
+pub fn save(path: &Path, layout: &Layout, bitfield: &Bitfield, completed: bool) -> Result<(), Error> {
+    let files = file_stamps(layout).ok_or_else(|| TorrentError(format!("can't stat files for {}", path.display())))?;
+    let data = ResumeData {
+        bitfield: hex::encode(bitfield.as_bytes()),
+        files,
+        completed,
+    };
+    let tmp = path.with_extension("resume.tmp");
+    fs::write(&tmp, serde_json::to_vec(&data)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Данные resume-файла, если файлы на диске с тех пор не менялись
+pub fn load(path: &Path, layout: &Layout) -> Option<Resume> {
+    let data: ResumeData = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+    let bitfield = Bitfield::from_bytes(&hex::decode(&data.bitfield).ok()?, layout.pieces_count()).ok()?;
+    if file_stamps(layout)? != data.files {
+        return None;
+    }
+    Some(Resume { have: bitfield, completed: data.completed })
+}
+
+/// Без resume-файла - полная перепроверка; о неотправленном completed тогда уже не узнать
+pub fn restore(path: &Path, layout: &Layout) -> Resume {
+    load(path, layout).unwrap_or_else(|| Resume { have: layout.recheck(), completed: false })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use torrent::files::test::{make_layout, temp_dir};
+
+    #[test]
+    fn test_trust_matching_stamps() {
+        let dir = temp_dir();
+        let layout = make_layout(&dir, 4, &[("a", b"01234567")]);
+        layout.write_piece(0, b"0123").unwrap();
+        layout.write_piece(1, b"4567").unwrap();
+        let path = resume_path(&dir, "hash");
+        let mut bitfield = Bitfield::new(2);
+        bitfield.set(0).unwrap();
+        save(&path, &layout, &bitfield, false).unwrap();
+        //resume-файлу верим на слово, даже если он "врет"
+        assert_eq!(Some(bitfield.clone()), load(&path, &layout).map(|resume| resume.have));
+        assert_eq!(bitfield, restore(&path, &layout).have);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_recheck_on_mismatch() {
+        let dir = temp_dir();
+        let layout = make_layout(&dir, 4, &[("a", b"01234567")]);
+        layout.write_piece(0, b"0123").unwrap();
+        let path = resume_path(&dir, "hash");
+        save(&path, &layout, &Bitfield::new(2), true).unwrap();
+        layout.write_piece(1, b"4567").unwrap(); //размер файла поменялся
+        assert_eq!(None, load(&path, &layout));
+        assert_eq!(Resume { have: Bitfield::full(2), completed: false }, restore(&path, &layout));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let dir = temp_dir();
+        let layout = make_layout(&dir, 4, &[("a", b"01234567"), ("b", b"89ab")]);
+        layout.write_piece(0, b"0123").unwrap();
+        layout.write_piece(1, b"4567").unwrap();
+        let path = resume_path(&dir, "hash");
+        let mut bitfield = Bitfield::new(3);
+        bitfield.set(0).unwrap();
+        bitfield.set(1).unwrap();
+        save(&path, &layout, &bitfield, false).unwrap();
+        //файла b еще нет, и resume-файл об этом знает
+        assert_eq!(Some(bitfield), load(&path, &layout).map(|resume| resume.have));
+        layout.write_piece(2, b"89ab").unwrap();
+        assert_eq!(None, load(&path, &layout));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_pending_completed() {
+        let dir = temp_dir();
+        let layout = make_layout(&dir, 4, &[("a", b"0123")]);
+        layout.write_piece(0, b"0123").unwrap();
+        let path = resume_path(&dir, "hash");
+        save(&path, &layout, &Bitfield::full(1), true).unwrap();
+        assert_eq!(Some(Resume { have: Bitfield::full(1), completed: true }), load(&path, &layout));
+        //resume-файлы прежнего формата
+        fs::write(&path, r#"{"bitfield":"80","files":[null]}"#).unwrap();
+        fs::remove_file(dir.join("a")).unwrap();
+        assert_eq!(Some(Resume { have: Bitfield::full(1), completed: false }), load(&path, &layout));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use super::message::Capability;
+use super::peer::State;
+use super::peer_id::Client;
+
+/// Окно скользящего среднего, в секундах
+const AVERAGE_WINDOW: f64 = 20.0;
+
+/// Скорость по секундным отсчетам: последняя полная секунда и экспоненциальное скользящее среднее
+pub struct RateMeter {
+    current: u64,
+    started: Instant,
+    last: u64,
+    average: f64,
+}
+
+impl RateMeter {
+    pub fn new(now: Instant) -> Self {
+        RateMeter { current: 0, started: now, last: 0, average: 0.0 }
+    }
+
+    pub fn add(&mut self, bytes: u64, now: Instant) {
+        self.tick(now);
+        self.current += bytes;
+    }
+
+    fn tick(&mut self, now: Instant) {
+        if now < self.started {
+            return;
+        }
+        let seconds = (now - self.started).as_secs();
+        if seconds == 0 {
+            return;
+        }
+        let alpha = 2.0 / (AVERAGE_WINDOW + 1.0);
+        self.average += alpha * (self.current as f64 - self.average);
+        //пустые секунды без передачи
+        self.average *= (1.0 - alpha).powi(std::cmp::min(seconds - 1, 1000) as i32);
+        self.last = if seconds == 1 { self.current } else { 0 };
+        self.current = 0;
+        self.started += Duration::from_secs(seconds);
+    }
+
+    pub fn rate(&mut self, now: Instant) -> u64 {
+        self.tick(now);
+        self.last
+    }
+
+    pub fn average(&mut self, now: Instant) -> u64 {
+        self.tick(now);
+        self.average.round() as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackerStatus {
+    NotContacted,
+    Updating, //анонс в пути
+    Working,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackerStats {
+    pub url: String,
+    pub status: TrackerStatus,
+    pub message: Option<String>, //ошибка последнего анонса
+    pub warning: Option<String>,
+    pub failures: u32, //неудачных анонсов подряд
+    pub last_announce: Option<u64>, //unix time
+    pub next_announce: Option<u64>,
+    pub seeders: Option<usize>,
+    pub leechers: Option<usize>,
+}
+
+/// Момент по монотонным часам в unix time
+pub fn unix_time(at: Instant, now: Instant) -> Option<u64> {
+    let system = SystemTime::now();
+    let system = if at >= now { system + (at - now) } else { system - (now - at) };
+    system.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+/// Счетчики одного торрента
+pub struct Counters {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub wasted: u64, //куски, не прошедшие проверку хеша
+    pub download: RateMeter,
+    pub upload: RateMeter,
+}
+
+impl Counters {
+    pub fn new(now: Instant) -> Self {
+        Counters {
+            downloaded: 0,
+            uploaded: 0,
+            wasted: 0,
+            download: RateMeter::new(now),
+            upload: RateMeter::new(now),
+        }
+    }
+
+    pub fn on_download(&mut self, bytes: u64, now: Instant) {
+        self.downloaded += bytes;
+        self.download.add(bytes, now);
+    }
+
+    pub fn on_upload(&mut self, bytes: u64, now: Instant) {
+        self.uploaded += bytes;
+        self.upload.add(bytes, now);
+    }
+
+    /// Неверный кусок тоже занимал канал, поэтому попадает и в скорость
+    pub fn on_wasted(&mut self, bytes: u64, now: Instant) {
+        self.wasted += bytes;
+        self.download.add(bytes, now);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerCounts {
+    pub connected: usize,
+    pub known: usize,
+    pub seeds: usize,
+}
+
+/// Подключенный пир для диагностики
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerInfo {
+    pub addr: String,
+    pub id: String, //peer_id, непечатные байты экранированы
+    pub client: Option<Client>,
+    pub capabilities: Vec<Capability>,
+    pub seed: bool,
+    pub state: State,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentStats {
+    pub info_hash: String,
+    pub pieces: u32,
+    pub verified_pieces: u32,
+    pub progress: f64,
+    pub size: u64,
+    pub left: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub wasted: u64,
+    pub download_rate: u64,
+    pub download_rate_average: u64,
+    pub upload_rate: u64,
+    pub upload_rate_average: u64,
+    pub eta: Option<u64>, //секунд до конца при средней скорости; None - неизвестно
+    pub peers: PeerCounts,
+    pub trackers: Vec<TrackerStats>,
+}
+
+pub fn eta(left: u64, average_rate: u64) -> Option<u64> {
+    match (left, average_rate) {
+        (0, _) => Some(0),
+        (_, 0) => None,
+        (left, rate) => Some((left + rate - 1) / rate),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_meter() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut meter = RateMeter::new(start);
+        meter.add(1000, start);
+        meter.add(1000, start + Duration::from_millis(500));
+        assert_eq!(0, meter.rate(start + Duration::from_millis(900)));
+        assert_eq!(2000, meter.rate(start + second));
+        let first_average = meter.average(start + second);
+        assert!(first_average > 0 && first_average < 2000);
+        for i in 1..100 {
+            meter.add(2000, start + second * i);
+        }
+        assert_eq!(2000, meter.rate(start + second * 100));
+        assert_eq!(2000, meter.average(start + second * 100));
+        //после простоя скорость падает до нуля, среднее - постепенно
+        assert_eq!(0, meter.rate(start + second * 105));
+        let average = meter.average(start + second * 105);
+        assert!(average > 0 && average < 2000);
+        assert_eq!(0, meter.average(start + second * 2000));
+    }
+
+    #[test]
+    fn test_eta() {
+        assert_eq!(Some(0), eta(0, 0));
+        assert_eq!(None, eta(100, 0));
+        assert_eq!(Some(3), eta(250, 100));
+    }
+}
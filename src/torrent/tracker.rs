@@ -1,12 +1,8 @@
-extern crate nom_old;
-extern crate bencoders;
-
-use self::bencoders::*;
-use self::nom_old::IResult;
-use std::net::IpAddr;
+use bencode::{self, Dict, Value};
+use std::net::{IpAddr, SocketAddr};
 use bytes::Bytes;
-use std::collections::HashMap;
 use super::HashString;
+use super::peer_id::{self, Client};
 
 const ADDR_BYTES: usize = 4;
 const PORT_BYTES: usize = 2; //note that port is u16, @see invoke_port
@@ -17,7 +13,7 @@ fn invoke_port(bytes: &[u8]) -> u16 {
 }
 
 
-#[derive(Debug, Display)]
+#[derive(Debug, Display, Clone, Copy, PartialEq)]
 pub enum TrackerEvent {
     #[display(fmt = "started")]
     Started,
@@ -28,21 +24,56 @@ pub enum TrackerEvent {
 }
 
 pub struct AnnounceRequest {
-    info_hash: HashString,
-    peer_id: HashString,
-    port: u16,
-    uploaded: u64,
-    downloaded: u64,
-    left: u64,
-    //compact: usize, //1 or 0
-    //no_peer_id: usize, //1 or 0
-    event: TrackerEvent,
+    pub info_hash: HashString,
+    pub peer_id: HashString,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<TrackerEvent>, //None - очередной анонс по расписанию
     //ip,
-    numwant: Option<usize>,
+    pub numwant: Option<usize>,
     //default 50
-    key: Option<String>,
-    tracker_id: Option<String>,
+    pub key: Option<String>,
+    pub tracker_id: Option<String>,
+}
+
+impl AnnounceRequest {
+    /// Адрес GET-запроса; announce уже может содержать свои параметры
+    pub fn url(&self, announce: &str) -> String {
+        let mut url = format!(
+            "{}{}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+            announce,
+            if announce.contains('?') { '&' } else { '?' },
+            url_encode(&self.info_hash),
+            url_encode(&self.peer_id),
+            self.port,
+            self.uploaded,
+            self.downloaded,
+            self.left
+        );
+        if let Some(event) = self.event {
+            url += &format!("&event={}", event);
+        }
+        if let Some(numwant) = self.numwant {
+            url += &format!("&numwant={}", numwant);
+        }
+        if let Some(ref key) = self.key {
+            url += &format!("&key={}", url_encode(key.as_bytes()));
+        }
+        if let Some(ref tracker_id) = self.tracker_id {
+            url += &format!("&trackerid={}", url_encode(tracker_id.as_bytes()));
+        }
+        url
+    }
+}
 
+/// info_hash и peer_id - произвольные байты: кодируем все, кроме незарезервированных символов
+fn url_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|&c| match c {
+        b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'-' | b'.' | b'_' | b'~' => char::from(c).to_string(),
+        c => format!("%{:02X}", c),
+    }).collect()
 }
 
 #[derive(Debug,PartialEq)]
@@ -52,9 +83,19 @@ pub struct Peer {
     port: u16,
 }
 
+impl Peer {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+    /// Трекер сообщает peer_id только в некомпактной форме
+    pub fn client(&self) -> Option<Client> {
+        self.id.as_ref().and_then(peer_id::client)
+    }
+}
+
 #[derive(Debug, Fail, PartialEq)]
 pub enum AnnounceResponseError {
-    #[fail(display = "received error message from tracker: {}", 0)]
+    #[fail(display = "received error message from tracker: {}", _0)]
     FailureMessage(String),
     #[fail(display = "received invalid response from tracker")]
     Invalid,
@@ -76,70 +117,46 @@ pub enum AnnounceResponse {
 
 impl From<Bytes> for AnnounceResponse { //TODO: реализовать scrape
     fn from(bytes: Bytes) -> Self {
-        match bencoders::decode(bytes.as_ref()) {
-            IResult::Done(_, Bencode::Dict(dict)) => {
-                if let Some(Bencode::Bytes(reason)) = dict.get(b"failure reason".as_ref()) {
-                    let err = AnnounceResponseError::FailureMessage(
-                        String::from_utf8(reason.clone()).unwrap()
-                    );
-                    AnnounceResponse::Failure(err)
-                } else {
-                    response_from_dict(&dict).unwrap_or(
-                        AnnounceResponse::Failure(AnnounceResponseError::Invalid)
-                    )
-                }
-            }
-            _ => AnnounceResponse::Failure(AnnounceResponseError::Invalid),
+        //некоторые трекеры дописывают после словаря перевод строки - хвост не проверяем
+        let dict = match bencode::decode_prefix(&bytes) {
+            Ok((Value::Dict(dict), _)) => dict,
+            _ => return AnnounceResponse::Failure(AnnounceResponseError::Invalid),
+        };
+        if let Some(reason) = dict.get_bytes(b"failure reason") {
+            let err = AnnounceResponseError::FailureMessage(String::from_utf8_lossy(reason).into_owned());
+            return AnnounceResponse::Failure(err);
         }
+        response_from_dict(&dict).unwrap_or(
+            AnnounceResponse::Failure(AnnounceResponseError::Invalid)
+        )
     }
 }
 
-trait Translator<T> {
-    fn translate(self) -> Option<T>;
+fn count(dict: &Dict, key: &[u8]) -> Option<usize> {
+    dict.get_int(key).filter(|&n| n >= 0).map(|n| n as usize)
 }
 
-impl Translator<usize> for Option<&Bencode> {
-    fn translate(self) -> Option<usize> {
-        match self? {
-            &Bencode::Int(val) => Some(val as usize),
-            _ => None
-        }
-    }
-}
-
-impl Translator<String> for Option<&Bencode> {
-    fn translate(self) -> Option<String> {
-        match self? {
-            &Bencode::Bytes(ref val) => {
-                match String::from_utf8(val.to_vec()) {
-                    Ok(val) => Some(val),
-                    Err(_) => None,
-                }
-            }
-            _ => None
-        }
-    }
-}
-
-fn response_from_dict(dict: &HashMap<Vec<u8>, Bencode>) -> Option<AnnounceResponse> {
+fn response_from_dict(dict: &Dict) -> Option<AnnounceResponse> {
     Some(AnnounceResponse::Success {
-        warning_message: dict.get(b"warning message".as_ref()).translate(),
-        interval: dict.get(b"interval".as_ref()).translate()?,
-        min_interval: dict.get(b"min interval".as_ref()).translate(),
-        tracker_id: dict.get(b"tracker id".as_ref()).translate(),
-        complete: dict.get(b"complete".as_ref()).translate()?,
-        incomplete: dict.get(b"interval".as_ref()).translate()?,
-        peers: invoke_peers(dict.get(b"peers".as_ref())?)?, //TODO: Распарсить список пиров
+        warning_message: dict.get_str(b"warning message").map(ToString::to_string),
+        interval: count(dict, b"interval")?,
+        min_interval: count(dict, b"min interval"),
+        tracker_id: dict.get_str(b"tracker id").map(ToString::to_string),
+        complete: count(dict, b"complete")?,
+        incomplete: count(dict, b"incomplete")?,
+        peers: invoke_peers(dict.get(b"peers")?)?,
     })
 }
-fn invoke_peers(bencode: &Bencode) -> Option<Vec<Peer>> {
-    match bencode {
-        Bencode::Bytes(bytes) => {
+
+fn invoke_peers(value: &Value) -> Option<Vec<Peer>> {
+    match value {
+        //компактная форма: 4 байта адреса и 2 байта порта на пира
+        Value::Bytes(bytes) => {
             if bytes.len() % PEER_BYTES != 0 {
                 return None;
             }
             let mut peers = Vec::with_capacity(bytes.len()/PEER_BYTES);
-            let mut slice = bytes.as_slice();
+            let mut slice = bytes.as_ref();
             while slice.len() > 0 {
                 let (peer, new_slice) = slice.split_at(PEER_BYTES);
                 slice = new_slice;
@@ -154,7 +171,20 @@ fn invoke_peers(bencode: &Bencode) -> Option<Vec<Peer>> {
             }
             Some(peers)
         },
-        Bencode::Dict(_) => unimplemented!(),
+        //словари с "peer id", "ip" (адрес или имя) и "port"; непонятные записи пропускаем
+        Value::List(list) => Some(list.iter()
+            .filter_map(Value::as_dict)
+            .filter_map(|peer| {
+                let id = peer.get_bytes(b"peer id").filter(|id| id.len() == 20).map(|id| {
+                    let mut hash: HashString = Default::default();
+                    hash.copy_from_slice(id);
+                    hash
+                });
+                let ip = peer.get_str(b"ip")?.parse().ok()?;
+                let port = peer.get_int(b"port").filter(|&p| p > 0 && p <= 0xffff)? as u16;
+                Some(Peer { id, ip, port })
+            })
+            .collect()),
         _ => None,
     }
 }
@@ -174,7 +204,7 @@ fn test_parse() {
             min_interval: Some(1313),
             tracker_id: None,
             complete: 30,
-            incomplete: 2627,
+            incomplete: 21,
             peers: vec![
                 Peer {id: None, ip: [97,51,102,120].into(), port: 8498},
                 Peer {id: None, ip: [98,53,105,100].into(), port: 8257},
@@ -182,6 +212,29 @@ fn test_parse() {
         }, bytes.into()
     );
 }
+#[test]
+fn test_parse_peer_dicts() {
+    let response = Bytes::from_static(
+        b"d8:completei1e10:incompletei0e8:intervali900e5:peersld2:ip9:127.0.0.17:peer id20:-MS0001-0123456789ab4:porti6881eed2:ip7:unknowneee"
+    );
+    match AnnounceResponse::from(response) {
+        AnnounceResponse::Success { peers, .. } => assert_eq!(vec![
+            Peer { id: Some(*b"-MS0001-0123456789ab"), ip: [127, 0, 0, 1].into(), port: 6881 },
+        ], peers),
+        other => panic!("unexpected {:?}", other),
+    }
+    let peer = Peer { id: Some(*b"-TR2940-0123456789ab"), ip: [127, 0, 0, 1].into(), port: 6881 };
+    assert_eq!("Transmission 2.94", peer.client().unwrap().to_string());
+    assert_eq!(
+        AnnounceResponse::Failure(AnnounceResponseError::FailureMessage("banned".to_string())),
+        Bytes::from_static(b"d14:failure reason6:bannede").into()
+    );
+    assert_eq!(
+        AnnounceResponse::Failure(AnnounceResponseError::Invalid),
+        Bytes::from_static(b"d8:intervali900e").into()
+    );
+}
+
 #[test]
 fn test_slice() {
     let mut slice = b"bugogablablazazaza".as_ref();
@@ -197,4 +250,28 @@ fn test_slice() {
         buf.push(chunk);
     }
     assert_eq!(check,buf);
-}
\ No newline at end of file
+}
+#[test]
+fn test_request_url() {
+    let mut request = AnnounceRequest {
+        info_hash: *b"\x12\x34\x56\x78\x9a\xbc\xde\xf1\x23\x45\x67\x89\xab\xcd\xef\x12\x34\x56\x78\x9a",
+        peer_id: *b"-MS0001-a&b=c d~.-_x",
+        port: 6882,
+        uploaded: 1,
+        downloaded: 2,
+        left: 3,
+        event: Some(TrackerEvent::Started),
+        numwant: None,
+        key: None,
+        tracker_id: None,
+    };
+    assert_eq!(
+        "http://t/announce?info_hash=%124Vx%9A%BC%DE%F1%23Eg%89%AB%CD%EF%124Vx%9A&peer_id=-MS0001-a%26b%3Dc%20d~.-_x\
+         &port=6882&uploaded=1&downloaded=2&left=3&compact=1&event=started",
+        request.url("http://t/announce")
+    );
+    request.event = None;
+    request.tracker_id = Some("a b".to_string());
+    assert!(request.url("http://t/announce?passkey=1").starts_with("http://t/announce?passkey=1&info_hash="));
+    assert!(request.url("http://t/announce").ends_with("&left=3&compact=1&trackerid=a%20b"));
+}